@@ -1,15 +1,15 @@
 use std::fs::File;
 use std::io::prelude::*;
+use std::time::Duration;
 use async_std::fs;
 use base64;
 use clap::{Parser, Subcommand};
 use cli_clipboard;
-use openssl::pkey::PKey;
-use openssl::x509::X509;
 
 use bswitch::api::*;
 use bswitch::bks::keystore::*;
 use bswitch::keygen::*;
+use bswitch::mock::*;
 use bswitch::protocol::*;
 
 // fn textwrap(input: &str) -> String {
@@ -47,6 +47,54 @@ use bswitch::protocol::*;
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+    // Password protecting the device key file, register prompts for it when it isn't
+    // set. Prefer the environment variable, arguments show up in ps and shell history
+    #[clap(long, global = true, env = IDENTITY_PASSWORD_ENV, hide_env_values = true)]
+    identity_password: Option<String>,
+}
+
+impl Cli {
+    // Key files written before the password was configurable use the default one
+    fn identity_password(&self) -> &str {
+        self.identity_password.as_deref().unwrap_or_else(|| {
+            eprintln!("no identity password given, using the default one");
+            DEFAULT_IDENTITY_PASSWORD
+        })
+    }
+
+    // The password for a new key file is never defaulted
+    fn new_identity_password(&self) -> std::io::Result<String> {
+        if let Some(password) = &self.identity_password {
+            return Ok(password.to_string());
+        }
+        loop {
+            let password = rpassword::prompt_password("Password for the device key: ")?;
+            if password.is_empty() {
+                eprintln!("the password can't be empty");
+                continue;
+            }
+            if rpassword::prompt_password("Repeat the password: ")? == password {
+                return Ok(password);
+            }
+            eprintln!("the passwords don't match");
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum ShutterCommand {
+    Open,
+    Close,
+    // Moves to the open percentage, prints the current one when not given
+    Position { position: Option<u8> },
+}
+
+#[derive(Subcommand)]
+enum TimedPowerCommand {
+    Start { minutes: u32 },
+    Cancel,
+    // Prints the minutes left, 0 when the unit is off
+    Remaining,
 }
 
 #[derive(Subcommand)]
@@ -62,6 +110,8 @@ enum Commands {
     SendCommand {
         #[clap(long)]
         ip: Option<String>,
+        #[clap(long, default_value_t = PRIORITY_NORMAL)]
+        priority: u8,
         certificate_path: String,
         message: String,
     },
@@ -73,24 +123,65 @@ enum Commands {
     TurnOn {
         #[clap(long)]
         ip: Option<String>,
+        // Looked up from the central unit when not given, the numeric type code the
+        // central unit reports in get-all-units
+        #[clap(long = "type")]
+        unit_type: Option<UnitType>,
         certificate_path: String,
-        #[clap(name = "type")]
-        unit_type: i32,
         unit_id: i32,
     },
     TurnOff {
+        #[clap(long)]
+        ip: Option<String>,
+        // Looked up from the central unit when not given, the numeric type code the
+        // central unit reports in get-all-units
+        #[clap(long = "type")]
+        unit_type: Option<UnitType>,
+        certificate_path: String,
+        unit_id: i32,
+    },
+    // Sets a dimmer to level, or moves it by --step, prints the level when neither is given
+    SetLevel {
+        #[clap(long)]
+        ip: Option<String>,
+        #[clap(long, conflicts_with = "level", allow_hyphen_values = true)]
+        step: Option<i32>,
+        certificate_path: String,
+        unit_id: i32,
+        level: Option<u8>,
+    },
+    Shutter {
+        #[clap(long)]
+        ip: Option<String>,
+        certificate_path: String,
+        unit_id: i32,
+        #[clap(subcommand)]
+        command: ShutterCommand,
+    },
+    TimedPower {
         #[clap(long)]
         ip: Option<String>,
         certificate_path: String,
-        #[clap(name = "type")]
-        unit_type: i32,
         unit_id: i32,
+        #[clap(subcommand)]
+        command: TimedPowerCommand,
     },
     GetGuestKey {
         apk_path: String,
         #[clap(short, long, name = "output")]
         output: Option<String>,
     },
+    Repin {
+        #[clap(long)]
+        ip: Option<String>,
+        certificate_path: String,
+    },
+    MockServer {
+        #[clap(long, default_value = "0.0.0.0")]
+        bind: String,
+        // JSON file in the GETA response format, a small sample model is used if omitted
+        data_path: Option<String>,
+    },
 }
 
 async fn get_cu_ip(ip: &Option<String>) -> Result<String> {
@@ -106,6 +197,36 @@ async fn get_cu_ip(ip: &Option<String>) -> Result<String> {
     })
 }
 
+fn pin_path(certificate_path: &str) -> String {
+    format!("{}.pin", certificate_path)
+}
+
+// Connects verifying the central unit certificate pinned at registration, identities
+// registered before pinning existed are pinned on their first connection
+async fn connect(ip: &str, certificate_path: &str, password: &str) -> Result<CuClient> {
+    let identity = load_device_identity(certificate_path, password).await?;
+    let pin_path = pin_path(certificate_path);
+    let pinned_fingerprint = load_pinned_fingerprint(&pin_path).await?;
+    let first_use = pinned_fingerprint.is_none();
+    let options = CuClientOptions {
+        pinned_fingerprint,
+        ..Default::default()
+    };
+    let client = CuClient::with_options(ip, CU_PORT, identity, options).await?;
+    if let (true, Some(fingerprint)) = (first_use, client.certificate_fingerprint()) {
+        save_pinned_fingerprint(&pin_path, fingerprint).await?;
+        eprintln!("pinned central unit certificate {} in {}", fingerprint, pin_path);
+    }
+    Ok(client)
+}
+
+async fn find_unit(client: &CuClient, unit_id: i32) -> UnitItem {
+    match client.get_all().await.unwrap().unit(unit_id) {
+        Some(item) => item.clone(),
+        None => panic!("no unit {} on the central unit", unit_id),
+    }
+}
+
 #[async_std::main]
 async fn main() {
     let cli = Cli::parse();
@@ -120,8 +241,9 @@ async fn main() {
             email,
             password,
         } => {
+            let identity_password = cli.new_identity_password().unwrap();
             let real_ip = get_cu_ip(ip).await.unwrap();
-            let (pk, cert) = generate_keypair(email, registration_name);
+            let (pk, cert) = generate_keypair(email, registration_name).unwrap();
             let params = RegisterDeviceParams {
                 name: email.to_owned(),
                 email: email.to_owned(),
@@ -129,37 +251,46 @@ async fn main() {
                 password: "".to_owned(),
                 pin: "".to_owned(),
                 device: registration_name.to_owned(),
-                device_certificate: base64::encode_config(cert.to_der().unwrap(), base64::URL_SAFE),
+                device_certificate: base64::encode_config(&cert, base64::URL_SAFE),
             };
             let client = get_default_https_client().await.unwrap();
             let resp = register_device(&client, &real_ip, &params).await.unwrap();
             println!("resp: {:?}", resp);
             println!("saving private key in device.key");
-            let pkcs12cert = openssl::pkcs12::Pkcs12::builder()
-                .build("1234", "device cert", &pk, &cert)
+            save_device_identity("./device.key", &pk, &cert, &identity_password)
+                .await
                 .unwrap();
-            fs::write("./device.key", pkcs12cert.to_der().unwrap())
+            // Pinned from the registration connection, later connections verify it
+            let fingerprint = &resp.certificate_fingerprint;
+            println!("pinning central unit certificate {} in device.key.pin", fingerprint);
+            save_pinned_fingerprint(&pin_path("./device.key"), fingerprint)
                 .await
                 .unwrap();
         }
         Commands::SendCommand {
             ip,
+            priority,
             certificate_path,
             message,
         } => {
             let ip = get_cu_ip(ip).await.unwrap();
-            let identity = get_device_identity(certificate_path).await.unwrap();
-            let mut client = CuClient::new(&ip, 23789, identity).await.unwrap();
-            let resp = client.request(message).await.unwrap();
-            println!("{}", resp);
+            let client = connect(&ip, certificate_path, cli.identity_password())
+                .await
+                .unwrap();
+            let resp = client
+                .request_with_priority(message, *priority)
+                .await
+                .unwrap();
+            println!("{}", resp.message);
         }
         Commands::GetAllUnits {
             ip,
             certificate_path,
         } => {
             let ip = get_cu_ip(ip).await.unwrap();
-            let identity = get_device_identity(certificate_path).await.unwrap();
-            let mut client = CuClient::new(&ip, 23789, identity).await.unwrap();
+            let client = connect(&ip, certificate_path, cli.identity_password())
+                .await
+                .unwrap();
             let resp = client.get_all().await.unwrap();
             for zone in &resp.place.as_ref().unwrap().zones {
                 for item in &zone.items {
@@ -174,14 +305,15 @@ async fn main() {
             unit_id,
         } => {
             let ip = get_cu_ip(ip).await.unwrap();
-            let identity = get_device_identity(certificate_path).await.unwrap();
-            let mut client = CuClient::new(&ip, 23789, identity).await.unwrap();
+            let client = connect(&ip, certificate_path, cli.identity_password())
+                .await
+                .unwrap();
+            let unit_type = match unit_type {
+                Some(unit_type) => *unit_type,
+                None => find_unit(&client, *unit_id).await.unit_type,
+            };
             let resp = client
-                .unit_operation(&UnitItemOperation {
-                    unit_id: *unit_id,
-                    unit_type: *unit_type,
-                    new_state: 100,
-                })
+                .unit_operation(&UnitItemOperation::new(*unit_id, unit_type, 100))
                 .await
                 .unwrap();
             println!("{:?}", resp)
@@ -193,18 +325,115 @@ async fn main() {
             unit_id,
         } => {
             let ip = get_cu_ip(ip).await.unwrap();
-            let identity = get_device_identity(certificate_path).await.unwrap();
-            let mut client = CuClient::new(&ip, 23789, identity).await.unwrap();
+            let client = connect(&ip, certificate_path, cli.identity_password())
+                .await
+                .unwrap();
+            let unit_type = match unit_type {
+                Some(unit_type) => *unit_type,
+                None => find_unit(&client, *unit_id).await.unit_type,
+            };
             let resp = client
-                .unit_operation(&UnitItemOperation {
-                    unit_id: *unit_id,
-                    unit_type: *unit_type,
-                    new_state: 0,
-                })
+                .unit_operation(&UnitItemOperation::new(*unit_id, unit_type, 0))
                 .await
                 .unwrap();
             println!("{:?}", resp)
         }
+        Commands::SetLevel {
+            ip,
+            step,
+            certificate_path,
+            unit_id,
+            level,
+        } => {
+            let ip = get_cu_ip(ip).await.unwrap();
+            let client = connect(&ip, certificate_path, cli.identity_password())
+                .await
+                .unwrap();
+            let level = match (level, step) {
+                (Some(level), _) => {
+                    client.set_brightness(*unit_id, *level).await.unwrap();
+                    (*level).min(MAX_BRIGHTNESS)
+                }
+                (None, Some(step)) => client.step_brightness(*unit_id, *step).await.unwrap(),
+                (None, None) => client.brightness(*unit_id).await.unwrap(),
+            };
+            println!("{}", level)
+        }
+        Commands::Shutter {
+            ip,
+            certificate_path,
+            unit_id,
+            command,
+        } => {
+            let ip = get_cu_ip(ip).await.unwrap();
+            let client = connect(&ip, certificate_path, cli.identity_password())
+                .await
+                .unwrap();
+            let resp = match command {
+                ShutterCommand::Open => client.open_shutter(*unit_id).await,
+                ShutterCommand::Close => client.close_shutter(*unit_id).await,
+                ShutterCommand::Position {
+                    position: Some(position),
+                } => client.set_shutter_position(*unit_id, *position).await,
+                ShutterCommand::Position { position: None } => {
+                    println!("{}", client.shutter_position(*unit_id).await.unwrap());
+                    return;
+                }
+            };
+            println!("{:?}", resp.unwrap())
+        }
+        Commands::TimedPower {
+            ip,
+            certificate_path,
+            unit_id,
+            command,
+        } => {
+            let ip = get_cu_ip(ip).await.unwrap();
+            let client = connect(&ip, certificate_path, cli.identity_password())
+                .await
+                .unwrap();
+            let resp = match command {
+                TimedPowerCommand::Start { minutes } => {
+                    let duration = Duration::from_secs(*minutes as u64 * 60);
+                    client.start_timed_power(*unit_id, duration).await
+                }
+                TimedPowerCommand::Cancel => client.cancel_timed_power(*unit_id).await,
+                TimedPowerCommand::Remaining => {
+                    let remaining = client.remaining_time(*unit_id).await.unwrap();
+                    println!("{}", remaining.as_secs() / 60);
+                    return;
+                }
+            };
+            println!("{:?}", resp.unwrap())
+        }
+        Commands::Repin {
+            ip,
+            certificate_path,
+        } => {
+            let ip = get_cu_ip(ip).await.unwrap();
+            let identity = load_device_identity(certificate_path, cli.identity_password())
+                .await
+                .unwrap();
+            let fingerprint = fetch_certificate_fingerprint(&ip, CU_PORT, identity)
+                .await
+                .unwrap();
+            save_pinned_fingerprint(&pin_path(certificate_path), &fingerprint)
+                .await
+                .unwrap();
+            println!("pinned central unit certificate {}", fingerprint);
+        }
+        Commands::MockServer { bind, data_path } => {
+            let mock = match data_path {
+                Some(path) => {
+                    MockCentralUnit::from_json(&fs::read_to_string(path).await.unwrap())
+                        .await
+                        .unwrap()
+                }
+                None => MockCentralUnit::new(default_cu_data()).await.unwrap(),
+            };
+            println!("serving mock central unit on {}", bind);
+            mock.run(bind).await.unwrap();
+        }
         Commands::GetGuestKey { apk_path , output} => {
             let mut zipfile = zip::ZipArchive::new(std::fs::File::open(apk_path).unwrap()).unwrap();
             let mut data: Vec<u8> = Vec::new();
@@ -225,12 +454,7 @@ async fn main() {
                     println!("failed to find certificate");
                     return;
                 }
-                let pk = PKey::private_key_from_pkcs8(pk).unwrap();
-                let cert = X509::from_der(&entry.cert_chain()[0].data()).unwrap();
-                let pkcs12cert = openssl::pkcs12::Pkcs12::builder()
-                    .build("1234", "guest cert", &pk, &cert)
-                    .unwrap();
-                let cert = pkcs12cert.to_der().unwrap();
+                let cert = export_pkcs12(pk, &entry.cert_chain()[0].data(), "1234").unwrap();
                 if let Some(filename) = output {
                     let mut file = File::create(filename).unwrap();
                     file.write(&cert).unwrap();
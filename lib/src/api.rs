@@ -1,17 +1,18 @@
-use async_native_tls;
-use async_std;
-use async_std::fs;
-use async_std::net::UdpSocket;
-use async_std::prelude::*;
 use base64;
-use reqwest::tls::Identity;
-use reqwest::Client;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::error::Error;
 use std::fmt::{self, Debug, Display};
 use std::str;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, instrument, warn};
 
-use crate::protocol::CuClient;
+use crate::bks::errors::BksError;
+use crate::codec::{DecodeError, FramingError};
+use crate::keygen::{export_pkcs12, KeyError};
+use crate::protocol::{certificate_fingerprint, CuClient};
+use crate::runtime::{self, UdpSocket};
+use crate::tls::{self, Identity};
 
 #[cfg(feature = "python")]
 use pyo3::create_exception;
@@ -24,21 +25,47 @@ use pyo3::prelude::*;
 pub struct ApiError {
     pub status: OperationStatus,
     pub message: String,
-    pub is_wrong_message_id: bool,
 }
 
 impl Display for ApiError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self.is_wrong_message_id {
-            false => f.write_fmt(format_args!(
-                "SwitchBee API error returned: {}",
-                self.status.to_string()
+        let status = self.status.to_string();
+        if self.message.is_empty() || self.message == status {
+            f.write_fmt(format_args!("SwitchBee API error returned: {}", status))
+        } else {
+            f.write_fmt(format_args!(
+                "SwitchBee API error returned: {} ({})",
+                status, self.message
+            ))
+        }
+    }
+}
+
+impl Error for ApiError {}
+
+#[derive(Debug)]
+pub struct CertificatePinError {
+    pub expected: String,
+    pub actual: Option<String>,
+}
+
+impl Display for CertificatePinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.actual {
+            Some(actual) => f.write_fmt(format_args!(
+                "Central unit certificate {} doesn't match the pinned certificate {}",
+                actual, self.expected
+            )),
+            None => f.write_fmt(format_args!(
+                "Central unit presented no certificate, expected {}",
+                self.expected
             )),
-            true => f.write_fmt(format_args!("Got bad message id from server")),
         }
     }
 }
 
+impl Error for CertificatePinError {}
+
 #[cfg(feature = "python")]
 create_exception!(libpybswitch, TlsError, PyException);
 
@@ -60,37 +87,163 @@ create_exception!(libpybswitch, Ut8DecodeError, PyException);
 #[cfg(feature = "python")]
 create_exception!(libpybswitch, Base64DecodeError, PyException);
 
+#[cfg(feature = "python")]
+create_exception!(libpybswitch, PyTimeoutError, PyException);
+
+#[cfg(feature = "python")]
+create_exception!(libpybswitch, PyFramingError, PyException);
+
+#[cfg(feature = "python")]
+create_exception!(libpybswitch, PyDecodeError, PyException);
+
+#[cfg(feature = "python")]
+create_exception!(libpybswitch, PyCertificatePinError, PyException);
+
+#[cfg(feature = "python")]
+create_exception!(libpybswitch, PyBksError, PyException);
+
 #[derive(Debug)]
 pub enum CombinedError {
-    IoError(async_std::io::Error),
+    IoError(std::io::Error),
     ReqwestError(reqwest::Error),
+    #[cfg(feature = "native-tls")]
     AsyncTlsError(async_native_tls::Error),
+    #[cfg(feature = "rustls")]
+    RustlsError(rustls::Error),
     SerdeJsonError(serde_json::Error),
     ApiError(ApiError),
     Utf8Error(str::Utf8Error),
     B64DecodeError(base64::DecodeError),
-    OpenSSLError(openssl::error::ErrorStack),
+    KeyError(KeyError),
+    Timeout(Duration),
+    FramingError(FramingError),
+    DecodeError(DecodeError),
+    CertificatePinError(CertificatePinError),
+    BksError(BksError),
+    // What was being done when the inner error happened, such as the request opcode
+    // and the central unit address
+    Context {
+        context: String,
+        source: Box<CombinedError>,
+    },
+}
+
+impl CombinedError {
+    pub fn context<C: Display>(self, context: C) -> Self {
+        CombinedError::Context {
+            context: context.to_string(),
+            source: Box::new(self),
+        }
+    }
+
+    // The error below any context, match on this instead of the error itself
+    pub fn root(&self) -> &CombinedError {
+        match self {
+            CombinedError::Context { source, .. } => source.root(),
+            err => err,
+        }
+    }
+}
+
+impl Display for CombinedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CombinedError::IoError(err) => Display::fmt(err, f),
+            CombinedError::ReqwestError(err) => Display::fmt(err, f),
+            #[cfg(feature = "native-tls")]
+            CombinedError::AsyncTlsError(err) => Display::fmt(err, f),
+            #[cfg(feature = "rustls")]
+            CombinedError::RustlsError(err) => Display::fmt(err, f),
+            CombinedError::SerdeJsonError(err) => Display::fmt(err, f),
+            CombinedError::ApiError(err) => Display::fmt(err, f),
+            CombinedError::Utf8Error(err) => Display::fmt(err, f),
+            CombinedError::B64DecodeError(err) => Display::fmt(err, f),
+            CombinedError::KeyError(err) => Display::fmt(err, f),
+            CombinedError::Timeout(after) => {
+                f.write_fmt(format_args!("request timed out after {:?}", after))
+            }
+            CombinedError::FramingError(err) => Display::fmt(err, f),
+            CombinedError::DecodeError(err) => Display::fmt(err, f),
+            CombinedError::CertificatePinError(err) => Display::fmt(err, f),
+            CombinedError::BksError(err) => Display::fmt(err, f),
+            CombinedError::Context { context, source } => {
+                f.write_fmt(format_args!("{}: {}", context, source))
+            }
+        }
+    }
+}
+
+// Wrapped errors are displayed in place, so the chain continues with their source
+impl Error for CombinedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CombinedError::IoError(err) => err.source(),
+            CombinedError::ReqwestError(err) => err.source(),
+            #[cfg(feature = "native-tls")]
+            CombinedError::AsyncTlsError(err) => err.source(),
+            #[cfg(feature = "rustls")]
+            CombinedError::RustlsError(err) => err.source(),
+            CombinedError::SerdeJsonError(err) => err.source(),
+            CombinedError::ApiError(err) => err.source(),
+            CombinedError::Utf8Error(err) => err.source(),
+            CombinedError::B64DecodeError(err) => err.source(),
+            CombinedError::KeyError(err) => err.source(),
+            CombinedError::Timeout(_) => None,
+            CombinedError::FramingError(err) => err.source(),
+            CombinedError::DecodeError(err) => err.source(),
+            CombinedError::CertificatePinError(err) => err.source(),
+            CombinedError::BksError(err) => err.source(),
+            CombinedError::Context { source, .. } => source.source(),
+        }
+    }
+}
+
+// Adds context to the error of a result, like anyhow::Context
+pub trait ResultExt<T> {
+    fn context<C: Display>(self, context: C) -> Result<T>;
+    fn with_context<C: Display, F: FnOnce() -> C>(self, context: F) -> Result<T>;
+}
+
+impl<T, E: Into<CombinedError>> ResultExt<T> for std::result::Result<T, E> {
+    fn context<C: Display>(self, context: C) -> Result<T> {
+        self.map_err(|e| e.into().context(context))
+    }
+
+    fn with_context<C: Display, F: FnOnce() -> C>(self, context: F) -> Result<T> {
+        self.map_err(|e| e.into().context(context()))
+    }
 }
 
 #[cfg(feature = "python")]
 impl From<CombinedError> for PyErr {
+    // The exception type follows the root error, the message keeps the context
     fn from(err: CombinedError) -> Self {
-        match err {
-            CombinedError::IoError(err) => IoError::new_err(err.to_string()),
-            CombinedError::ReqwestError(err) => HttpsError::new_err(err.to_string()),
-            CombinedError::AsyncTlsError(err) => TlsError::new_err(err.to_string()),
-            CombinedError::SerdeJsonError(err) => JSONDecodeError::new_err(err.to_string()),
-            CombinedError::ApiError(err) => PyApiError::new_err(err.to_string()),
-            CombinedError::Utf8Error(err) => Ut8DecodeError::new_err(err.to_string()),
-            CombinedError::B64DecodeError(err) => Base64DecodeError::new_err(err.to_string()),
-            CombinedError::OpenSSLError(err) => TlsError::new_err(err.to_string()),
+        let message = err.to_string();
+        match err.root() {
+            CombinedError::IoError(_) => IoError::new_err(message),
+            CombinedError::ReqwestError(_) => HttpsError::new_err(message),
+            #[cfg(feature = "native-tls")]
+            CombinedError::AsyncTlsError(_) => TlsError::new_err(message),
+            #[cfg(feature = "rustls")]
+            CombinedError::RustlsError(_) => TlsError::new_err(message),
+            CombinedError::SerdeJsonError(_) => JSONDecodeError::new_err(message),
+            CombinedError::ApiError(_) => PyApiError::new_err(message),
+            CombinedError::Utf8Error(_) => Ut8DecodeError::new_err(message),
+            CombinedError::B64DecodeError(_) => Base64DecodeError::new_err(message),
+            CombinedError::KeyError(_) => TlsError::new_err(message),
+            CombinedError::Timeout(_) => PyTimeoutError::new_err(message),
+            CombinedError::FramingError(_) => PyFramingError::new_err(message),
+            CombinedError::DecodeError(_) => PyDecodeError::new_err(message),
+            CombinedError::CertificatePinError(_) => PyCertificatePinError::new_err(message),
+            CombinedError::BksError(_) => PyBksError::new_err(message),
+            CombinedError::Context { .. } => PyException::new_err(message),
         }
     }
 }
 
-impl From<openssl::error::ErrorStack> for CombinedError {
-    fn from(e: openssl::error::ErrorStack) -> Self {
-        Self::OpenSSLError(e)
+impl From<KeyError> for CombinedError {
+    fn from(e: KeyError) -> Self {
+        Self::KeyError(e)
     }
 }
 
@@ -100,8 +253,8 @@ impl From<base64::DecodeError> for CombinedError {
     }
 }
 
-impl From<async_std::io::Error> for CombinedError {
-    fn from(e: async_std::io::Error) -> Self {
+impl From<std::io::Error> for CombinedError {
+    fn from(e: std::io::Error) -> Self {
         Self::IoError(e)
     }
 }
@@ -118,12 +271,38 @@ impl From<serde_json::Error> for CombinedError {
     }
 }
 
+#[cfg(feature = "native-tls")]
 impl From<async_native_tls::Error> for CombinedError {
     fn from(e: async_native_tls::Error) -> Self {
         Self::AsyncTlsError(e)
     }
 }
 
+#[cfg(feature = "rustls")]
+impl From<rustls::Error> for CombinedError {
+    fn from(e: rustls::Error) -> Self {
+        Self::RustlsError(e)
+    }
+}
+
+impl From<FramingError> for CombinedError {
+    fn from(e: FramingError) -> Self {
+        Self::FramingError(e)
+    }
+}
+
+impl From<DecodeError> for CombinedError {
+    fn from(e: DecodeError) -> Self {
+        Self::DecodeError(e)
+    }
+}
+
+impl From<BksError> for CombinedError {
+    fn from(e: BksError) -> Self {
+        Self::BksError(e)
+    }
+}
+
 impl From<str::Utf8Error> for CombinedError {
     fn from(e: str::Utf8Error) -> Self {
         Self::Utf8Error(e)
@@ -132,29 +311,342 @@ impl From<str::Utf8Error> for CombinedError {
 
 pub type Result<T> = std::result::Result<T, CombinedError>;
 
-#[derive(Debug, Deserialize, Clone)]
+// Central unit TLS protocol port
+pub const CU_PORT: u32 = 23789;
+// UDP port answering FIND broadcasts
+pub const DISCOVERY_PORT: u16 = 8872;
+// Address FIND probes are broadcast to
+pub const DISCOVERY_ADDRESS: &str = "255.255.255.255";
+// HTTPS port accepting device registration
+pub const REGISTRATION_PORT: u16 = 8443;
+
+// Password device identities used before it was configurable, still the default
+pub const DEFAULT_IDENTITY_PASSWORD: &str = "1234";
+
+pub const IDENTITY_ENV: &str = "BSWITCH_IDENTITY";
+pub const IDENTITY_PASSWORD_ENV: &str = "BSWITCH_IDENTITY_PASSWORD";
+pub const IDENTITY_CERT_ENV: &str = "BSWITCH_IDENTITY_CERT";
+pub const IDENTITY_KEY_ENV: &str = "BSWITCH_IDENTITY_KEY";
+
+// Item type codes used by the central unit, codes this library doesn't know are kept
+// as Unknown so they survive a round trip
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+#[serde(from = "i32", into = "i32")]
+pub enum UnitType {
+    Switch,
+    Dimmer,
+    Shutter,
+    TimedPower,
+    Thermostat,
+    Scenario,
+    Group,
+    IrDevice,
+    Unknown(i32),
+}
+
+// SwitchBee doesn't publish the type codes of its protocol and this table hasn't been
+// checked against a GETA reply from a real central unit yet, so it only names units
+// for display. Operations send the type the central unit reported for the unit and
+// never a code from here. Codes missing here are kept as UnitType::Unknown
+const UNIT_TYPES: [(UnitType, i32, &str); 8] = [
+    (UnitType::Switch, 1, "switch"),
+    (UnitType::Dimmer, 2, "dimmer"),
+    (UnitType::Shutter, 3, "shutter"),
+    (UnitType::TimedPower, 4, "timed-power"),
+    (UnitType::Thermostat, 5, "thermostat"),
+    (UnitType::Scenario, 6, "scenario"),
+    (UnitType::Group, 7, "group"),
+    (UnitType::IrDevice, 8, "ir-device"),
+];
+
+impl UnitType {
+    pub fn code(&self) -> i32 {
+        match self {
+            UnitType::Unknown(code) => *code,
+            known => UNIT_TYPES
+                .iter()
+                .find(|(unit_type, _, _)| unit_type == known)
+                .map(|(_, code, _)| *code)
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        UNIT_TYPES
+            .iter()
+            .find(|(unit_type, _, _)| unit_type == self)
+            .map(|(_, _, name)| *name)
+            .unwrap_or("unknown")
+    }
+}
+
+impl From<i32> for UnitType {
+    fn from(code: i32) -> Self {
+        UNIT_TYPES
+            .iter()
+            .find(|(_, known, _)| *known == code)
+            .map(|(unit_type, _, _)| *unit_type)
+            .unwrap_or(UnitType::Unknown(code))
+    }
+}
+
+impl From<UnitType> for i32 {
+    fn from(unit_type: UnitType) -> Self {
+        unit_type.code()
+    }
+}
+
+impl Display for UnitType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnitType::Unknown(code) => f.write_fmt(format_args!("unknown({})", code)),
+            known => f.write_str(known.name()),
+        }
+    }
+}
+
+// Only numeric type codes as reported in GETA are accepted, names would have to be
+// mapped to the unverified codes of UNIT_TYPES
+impl str::FromStr for UnitType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        s.parse::<i32>()
+            .map(UnitType::from)
+            .map_err(|_| format!("invalid unit type code {}", s))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UnitItem {
     pub name: String,
     #[serde(rename = "unitId")]
     pub unit_id: i32,
     pub value: i32,
     #[serde(rename = "type")]
-    pub unit_type: i32,
+    pub unit_type: UnitType,
+    // Only reported for thermostats, their power is the value like for switches. The
+    // format is a guess, see ThermostatState
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thermostat: Option<ThermostatState>,
+}
+
+// Values this library doesn't know are kept as reported, so one thermostat in an
+// unexpected state doesn't fail the whole GETA response and still round trips
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(from = "String", into = "String")]
+pub enum ThermostatMode {
+    Cool,
+    Heat,
+    Dry,
+    Fan,
+    Auto,
+    Unknown(String),
+}
+
+impl ThermostatMode {
+    pub fn name(&self) -> &str {
+        match self {
+            ThermostatMode::Cool => "cool",
+            ThermostatMode::Heat => "heat",
+            ThermostatMode::Dry => "dry",
+            ThermostatMode::Fan => "fan",
+            ThermostatMode::Auto => "auto",
+            ThermostatMode::Unknown(mode) => mode,
+        }
+    }
+}
+
+impl Display for ThermostatMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Only accepts the known modes, in any case
+impl str::FromStr for ThermostatMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cool" => Ok(ThermostatMode::Cool),
+            "heat" => Ok(ThermostatMode::Heat),
+            "dry" => Ok(ThermostatMode::Dry),
+            "fan" => Ok(ThermostatMode::Fan),
+            "auto" => Ok(ThermostatMode::Auto),
+            _ => Err(format!("unknown thermostat mode {}", s)),
+        }
+    }
+}
+
+impl From<String> for ThermostatMode {
+    fn from(mode: String) -> Self {
+        mode.parse().unwrap_or(ThermostatMode::Unknown(mode))
+    }
+}
+
+impl From<ThermostatMode> for String {
+    fn from(mode: ThermostatMode) -> Self {
+        match mode {
+            ThermostatMode::Unknown(mode) => mode,
+            known => known.name().to_ascii_uppercase(),
+        }
+    }
+}
+
+// Kept as reported when unknown, like ThermostatMode
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(from = "String", into = "String")]
+pub enum FanSpeed {
+    Low,
+    Medium,
+    High,
+    Auto,
+    Unknown(String),
+}
+
+impl FanSpeed {
+    pub fn name(&self) -> &str {
+        match self {
+            FanSpeed::Low => "low",
+            FanSpeed::Medium => "medium",
+            FanSpeed::High => "high",
+            FanSpeed::Auto => "auto",
+            FanSpeed::Unknown(fan) => fan,
+        }
+    }
+}
+
+impl Display for FanSpeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Only accepts the known speeds, in any case
+impl str::FromStr for FanSpeed {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "low" => Ok(FanSpeed::Low),
+            "medium" => Ok(FanSpeed::Medium),
+            "high" => Ok(FanSpeed::High),
+            "auto" => Ok(FanSpeed::Auto),
+            _ => Err(format!("unknown fan speed {}", s)),
+        }
+    }
+}
+
+impl From<String> for FanSpeed {
+    fn from(fan: String) -> Self {
+        fan.parse().unwrap_or(FanSpeed::Unknown(fan))
+    }
+}
+
+impl From<FanSpeed> for String {
+    fn from(fan: FanSpeed) -> Self {
+        match fan {
+            FanSpeed::Unknown(fan) => fan,
+            known => known.name().to_ascii_uppercase(),
+        }
+    }
+}
+
+// Temperatures are in degrees Celsius. Experimental: the "thermostat" object hasn't
+// been checked against a GETA reply or UNOP of a real central unit, so controlling
+// thermostats needs the experimental feature
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct ThermostatState {
+    pub mode: ThermostatMode,
+    pub fan: FanSpeed,
+    #[serde(rename = "targetTemperature")]
+    pub target_temperature: i32,
+    // Measured by the thermostat, ignored by the central unit in operations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+}
+
+// Dimmer levels are percentages
+pub const MAX_BRIGHTNESS: u8 = 100;
+
+// Shutter positions are the open percentage
+pub const SHUTTER_OPEN: u8 = 100;
+pub const SHUTTER_CLOSED: u8 = 0;
+
+// Timed power units take their duration and report the time left in whole minutes,
+// 0 is off
+const MINUTE: Duration = Duration::from_secs(60);
+
+fn timed_power_minutes(duration: Duration) -> Result<i32> {
+    let invalid = |reason: &str| {
+        unit_error(
+            OperationStatus::ERROR,
+            format!("invalid timed power duration {:?}, {}", duration, reason),
+        )
+    };
+    if duration.is_zero() {
+        return Err(invalid("use cancel_timed_power to turn the unit off"));
+    }
+    if duration.subsec_nanos() != 0 || !duration.as_secs().is_multiple_of(MINUTE.as_secs()) {
+        return Err(invalid("it must be whole minutes"));
+    }
+    i32::try_from(duration.as_secs() / MINUTE.as_secs()).map_err(|_| invalid("it is too long"))
+}
+
+impl UnitItem {
+    pub fn is_on(&self) -> bool {
+        self.value > 0
+    }
+
+    // Dimmer levels and shutter positions
+    fn percentage(&self) -> u8 {
+        self.value.clamp(0, 100) as u8
+    }
+
+    fn minutes(&self) -> Duration {
+        MINUTE * self.value.max(0) as u32
+    }
+
+    // Current level of a dimmer, None for units named otherwise by UnitType
+    pub fn brightness(&self) -> Option<u8> {
+        match self.unit_type {
+            UnitType::Dimmer => Some(self.percentage()),
+            _ => None,
+        }
+    }
+
+    // Open percentage of a shutter, None for units named otherwise by UnitType
+    pub fn shutter_position(&self) -> Option<u8> {
+        match self.unit_type {
+            UnitType::Shutter => Some(self.percentage()),
+            _ => None,
+        }
+    }
+
+    // Time left before a timed power unit turns off, None for units named otherwise
+    // by UnitType
+    pub fn remaining_time(&self) -> Option<Duration> {
+        match self.unit_type {
+            UnitType::TimedPower => Some(self.minutes()),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Zone {
     pub id: i32,
     pub name: String,
     pub items: Vec<UnitItem>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Place {
     pub zones: Vec<Zone>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[allow(non_snake_case, dead_code)]
 #[cfg_attr(feature = "python", pyclass)]
 pub struct CuData {
@@ -174,8 +666,8 @@ pub struct CuData {
     lat: f64,
     #[serde(default)]
     lon: f64,
-    mac: String,
-    name: String,
+    pub mac: String,
+    pub name: String,
     #[serde(default)]
     pin: i32,
     #[serde(default)]
@@ -189,6 +681,19 @@ pub struct CuData {
     pub place: Option<Place>,
 }
 
+impl CuData {
+    pub fn units(&self) -> impl Iterator<Item = &UnitItem> {
+        self.place
+            .iter()
+            .flat_map(|place| place.zones.iter())
+            .flat_map(|zone| zone.items.iter())
+    }
+
+    pub fn unit(&self, unit_id: i32) -> Option<&UnitItem> {
+        self.units().find(|item| item.unit_id == unit_id)
+    }
+}
+
 #[cfg(feature = "python")]
 #[pymethods]
 impl CuData {
@@ -202,14 +707,66 @@ impl CuData {
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct UnitItemOperation {
     #[serde(rename = "newState")]
     pub new_state: i32,
     #[serde(rename = "type")]
-    pub unit_type: i32,
+    pub unit_type: UnitType,
     #[serde(rename = "unitId")]
     pub unit_id: i32,
+    // Settings of a thermostat, sent along with its power in new_state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thermostat: Option<ThermostatState>,
+}
+
+impl UnitItemOperation {
+    pub fn new(unit_id: i32, unit_type: UnitType, new_state: i32) -> Self {
+        UnitItemOperation {
+            new_state,
+            unit_type,
+            unit_id,
+            thermostat: None,
+        }
+    }
+
+    // Operations on a unit as reported in GETA, with the type it was reported with
+    pub fn for_item(item: &UnitItem, new_state: i32) -> Self {
+        Self::new(item.unit_id, item.unit_type, new_state)
+    }
+
+    // Levels above MAX_BRIGHTNESS are clamped
+    pub fn brightness(item: &UnitItem, level: u8) -> Self {
+        Self::for_item(item, level.min(MAX_BRIGHTNESS) as i32)
+    }
+
+    // Positions above SHUTTER_OPEN are clamped
+    pub fn shutter_position(item: &UnitItem, position: u8) -> Self {
+        Self::for_item(item, position.min(SHUTTER_OPEN) as i32)
+    }
+
+    // The central unit takes whole minutes, other durations and zero are rejected
+    // instead of rounded, cancel_timed_power turns the unit off
+    pub fn timed_power(item: &UnitItem, duration: Duration) -> Result<Self> {
+        let minutes = timed_power_minutes(duration)?;
+        Ok(Self::for_item(item, minutes))
+    }
+
+    pub fn cancel_timed_power(item: &UnitItem) -> Self {
+        Self::for_item(item, 0)
+    }
+
+    // Keeps the power the thermostat currently has
+    #[cfg(feature = "experimental")]
+    pub fn thermostat(item: &UnitItem, state: ThermostatState) -> Self {
+        UnitItemOperation {
+            thermostat: Some(ThermostatState {
+                temperature: None,
+                ..state
+            }),
+            ..Self::for_item(item, item.value)
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -292,7 +849,7 @@ impl RegisterDeviceParams {
     }
 }
 
-#[derive(Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
 pub enum OperationStatus {
     OK,
     ERROR,
@@ -318,7 +875,7 @@ impl fmt::Display for OperationStatus {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[cfg_attr(feature = "python", pyclass)]
 pub struct CuStatus {
     pub status: OperationStatus,
@@ -329,6 +886,10 @@ pub struct CuStatus {
 pub struct RegisterDeviceResponse {
     #[serde(flatten)]
     pub status: CuStatus,
+    // Fingerprint of the certificate the central unit presented during registration,
+    // pin it to verify the later connections
+    #[serde(skip)]
+    pub certificate_fingerprint: String,
 }
 
 #[cfg(feature = "python")]
@@ -339,29 +900,114 @@ impl RegisterDeviceResponse {
     }
 }
 
-async fn collect_responses(socket: UdpSocket, exit_on_first: bool) -> Result<Vec<CuData>> {
+// A central unit operation, sent as the opcode followed by the JSON encoded request
+pub trait Command {
+    const OPCODE: &'static str;
+    // Whether the command can safely be sent again when the response was lost
+    const IDEMPOTENT: bool = false;
+    type Request: Serialize;
+    type Response: DeserializeOwned;
+
+    fn encode(request: &Self::Request) -> Result<String> {
+        Ok(Self::OPCODE.to_string() + &serde_json::to_string(request)?)
+    }
+
+    fn decode(response: &str) -> Result<Self::Response> {
+        Ok(serde_json::from_str(response)?)
+    }
+}
+
+fn check_status(status: &CuStatus, message: String) -> Result<()> {
+    if status.status != OperationStatus::OK {
+        return Err(CombinedError::ApiError(ApiError {
+            message,
+            status: status.status,
+        }));
+    }
+    Ok(())
+}
+
+pub struct GetAll;
+
+impl Command for GetAll {
+    const OPCODE: &'static str = "GETA";
+    const IDEMPOTENT: bool = true;
+    type Request = ();
+    type Response = CuData;
+
+    fn encode(_: &()) -> Result<String> {
+        Ok(Self::OPCODE.to_string())
+    }
+}
+
+pub struct UnitOperation;
+
+impl Command for UnitOperation {
+    const OPCODE: &'static str = "UNOP";
+    type Request = UnitItemOperation;
+    type Response = CuStatus;
+
+    fn decode(response: &str) -> Result<CuStatus> {
+        let resp: CuStatus = serde_json::from_str(response)?;
+        check_status(&resp, resp.status.to_string())?;
+        Ok(resp)
+    }
+}
+
+pub struct RegisterDevice;
+
+impl Command for RegisterDevice {
+    const OPCODE: &'static str = "REGD";
+    type Request = RegisterDeviceParams;
+    type Response = RegisterDeviceResponse;
+
+    fn decode(response: &str) -> Result<RegisterDeviceResponse> {
+        let resp: RegisterDeviceResponse = serde_json::from_str(response)?;
+        check_status(&resp.status, response.to_string())?;
+        Ok(resp)
+    }
+}
+
+// Time discovery waits for central units to answer
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Collects answers until the timeout or until done returns true for the last one
+async fn collect_responses<F>(
+    socket: UdpSocket,
+    timeout: Duration,
+    mut done: F,
+) -> Result<Vec<CuData>>
+where
+    F: FnMut(&CuData) -> bool,
+{
     let mut buf: [u8; 10000] = [0; 10000];
 
     let mut results: Vec<CuData> = Vec::new();
 
-    let timeout = SystemTime::now()
-        .checked_add(Duration::from_secs(5))
-        .unwrap();
+    let timeout = Instant::now() + timeout;
 
-    loop {
-        let current_dur = match timeout.duration_since(SystemTime::now()) {
-            Ok(val) => val,
-            Err(_) => break,
-        };
-        let (data_size, ip) = match socket.recv_from(&mut buf).timeout(current_dur).await {
-            Ok(result) => result,
-            Err(_) => break,
+    while let Some(current_dur) = timeout.checked_duration_since(Instant::now()) {
+        let (data_size, ip) = match runtime::timeout(current_dur, socket.recv_from(&mut buf)).await
+        {
+            Some(result) => result,
+            None => break,
         }?;
-        let str_data = str::from_utf8(&buf[0..data_size]).unwrap();
-        let mut cudata: CuData = serde_json::from_str(str_data).unwrap();
+        // Anything else may answer on the discovery port, skip what isn't a central unit
+        let mut cudata: CuData = match str::from_utf8(&buf[0..data_size])
+            .map_err(CombinedError::from)
+            .and_then(|data| Ok(serde_json::from_str(data)?))
+        {
+            Ok(cudata) => cudata,
+            Err(e) => {
+                debug!(%ip, error = %e, "ignoring invalid discovery response");
+                continue;
+            }
+        };
         cudata.CUIP = ip.ip().to_string();
+        debug!(ip = %cudata.CUIP, mac = %cudata.mac, name = %cudata.name, "central unit found");
+        let found = done(&cudata);
         results.push(cudata);
-        if exit_on_first {
+        if found {
             break;
         }
     }
@@ -370,31 +1016,108 @@ async fn collect_responses(socket: UdpSocket, exit_on_first: bool) -> Result<Vec
 }
 
 pub async fn discover_central_units(exit_on_first: bool) -> Result<Vec<CuData>> {
+    let address = format!("{}:{}", DISCOVERY_ADDRESS, DISCOVERY_PORT);
+    discover_central_units_at(&address, exit_on_first).await
+}
+
+// Sends the FIND probe to the given ip:port instead of broadcasting it, such as to a
+// mock central unit listening on localhost
+#[instrument(level = "debug")]
+pub async fn discover_central_units_at(address: &str, exit_on_first: bool) -> Result<Vec<CuData>> {
+    let socket = probe(address).await?;
+    collect_responses(socket, DISCOVERY_TIMEOUT, |_| exit_on_first).await
+}
+
+// Looks a central unit up by MAC, returning as soon as it answers
+#[instrument(level = "debug")]
+pub async fn find_central_unit(
+    address: &str,
+    mac: &str,
+    timeout: Duration,
+) -> Result<Option<CuData>> {
+    let socket = probe(address).await?;
+    let units = collect_responses(socket, timeout, |cu| cu.mac == mac).await?;
+    Ok(units.into_iter().find(|cu| cu.mac == mac))
+}
+
+async fn probe(address: &str) -> Result<UdpSocket> {
     let socket = UdpSocket::bind("0.0.0.0:0").await?;
     socket.set_broadcast(true)?;
-    socket
-        .send_to("FIND".as_bytes(), "255.255.255.255:8872".to_string())
-        .await?;
-    Ok(collect_responses(socket, exit_on_first).await?)
+    socket.send_to("FIND".as_bytes(), address).await?;
+    Ok(socket)
 }
 
 pub async fn get_guest_identity() -> Result<Identity> {
     let contents = include_bytes!("key");
-    Ok(reqwest::Identity::from_pkcs12_der(contents, "1234")?)
+    Identity::from_pkcs12(contents, "1234")
 }
 
 // Client used for device registration, requires the guest certificate
 pub async fn get_default_https_client() -> Result<reqwest::Client> {
-    Ok(Client::builder()
-        // .add_root_certificate(cert)
-        .danger_accept_invalid_certs(true)
-        .identity(get_guest_identity().await?)
-        .build()?)
+    Ok(tls::https_client_builder(&get_guest_identity().await?)?.build()?)
+}
+
+// Only for key files written before the password was configurable, new ones are
+// loaded with load_device_identity
+pub async fn get_device_identity(path: &str) -> Result<Identity> {
+    warn!(path, "loading device identity with the default password");
+    load_device_identity(path, DEFAULT_IDENTITY_PASSWORD).await
+}
+
+pub async fn load_device_identity(path: &str, password: &str) -> Result<Identity> {
+    let contents = runtime::read(path)
+        .await
+        .with_context(|| format!("reading device identity {}", path))?;
+    Identity::from_pkcs12(&contents, password)
+        .with_context(|| format!("loading device identity {}", path))
 }
 
-pub async fn get_device_identity(path: &str) -> Result<async_native_tls::Identity> {
-    let contents = fs::read(path).await?;
-    Ok(async_native_tls::Identity::from_pkcs12(&contents, "1234")?)
+// Stores the registered key pair as PKCS#12 encrypted with the given password, see
+// keygen::generate_keypair
+pub async fn save_device_identity(
+    path: &str,
+    key: &[u8],
+    cert: &[u8],
+    password: &str,
+) -> Result<()> {
+    Ok(runtime::write(path, export_pkcs12(key, cert, password)?).await?)
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+// Reads an identity given as base64 PKCS#12 in BSWITCH_IDENTITY, or as a PEM pair in
+// BSWITCH_IDENTITY_CERT and BSWITCH_IDENTITY_KEY, None when neither is set. The
+// PKCS#12 password is required in BSWITCH_IDENTITY_PASSWORD
+pub fn identity_from_env() -> Result<Option<Identity>> {
+    if let Some(pkcs12) = env_var(IDENTITY_ENV) {
+        let password = env_var(IDENTITY_PASSWORD_ENV).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is set without {}", IDENTITY_ENV, IDENTITY_PASSWORD_ENV),
+            )
+        })?;
+        let pkcs12 = base64::decode(pkcs12.trim())?;
+        return Ok(Some(Identity::from_pkcs12(&pkcs12, &password)?));
+    }
+    match (env_var(IDENTITY_CERT_ENV), env_var(IDENTITY_KEY_ENV)) {
+        (Some(cert), Some(key)) => Ok(Some(Identity::from_pem(cert.as_bytes(), key.as_bytes())?)),
+        _ => Ok(None),
+    }
+}
+
+// Pinned fingerprints are stored as a single hex line
+pub async fn load_pinned_fingerprint(path: &str) -> Result<Option<String>> {
+    match runtime::read_to_string(path).await {
+        Ok(contents) => Ok(Some(contents.trim().to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn save_pinned_fingerprint(path: &str, fingerprint: &str) -> Result<()> {
+    Ok(runtime::write(path, fingerprint.to_string() + "\n").await?)
 }
 
 pub async fn register_device(
@@ -402,48 +1125,340 @@ pub async fn register_device(
     ip: &str,
     params: &RegisterDeviceParams,
 ) -> Result<RegisterDeviceResponse> {
-    let req_text = "REGD".to_string() + &serde_json::to_string(params)?;
+    register_device_at(client, ip, REGISTRATION_PORT, params).await
+}
+
+// Like register_device with a registration port other than REGISTRATION_PORT
+#[instrument(skip(client, params), fields(device = %params.device))]
+pub async fn register_device_at(
+    client: &reqwest::Client,
+    ip: &str,
+    port: u16,
+    params: &RegisterDeviceParams,
+) -> Result<RegisterDeviceResponse> {
     let req = match client
-        .post("https://".to_owned() + ip + ":8443/commands")
-        .body(req_text)
+        .post(format!("https://{}:{}/commands", ip, port))
+        .body(RegisterDevice::encode(params)?)
         .send()
         .await
     {
         Ok(val) => val,
         Err(e) => {
-            println!("Failed to send request {}\n", e);
-            return Err(CombinedError::ReqwestError(e));
+            warn!(error = %e, "failed to send registration request");
+            return Err(CombinedError::ReqwestError(e)
+                .context(format!("registering device with central unit {}", ip)));
         }
     };
-    let resp_text = req.text().await?;
-    let resp: RegisterDeviceResponse = serde_json::from_str(&resp_text)?;
-    if resp.status.status != OperationStatus::OK {
-        return Err(CombinedError::ApiError(ApiError {
-            message: resp_text,
-            status: resp.status.status,
-            is_wrong_message_id: false,
-        }));
+    let fingerprint = req
+        .extensions()
+        .get::<reqwest::tls::TlsInfo>()
+        .and_then(|info| info.peer_certificate())
+        .map(certificate_fingerprint)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("central unit {} presented no certificate", ip),
+            )
+        })?;
+    let response = RegisterDevice::decode(&req.text().await?);
+    match &response {
+        Ok(_) => info!(%fingerprint, "device registered"),
+        Err(e) => warn!(error = ?e, "registration rejected"),
+    }
+    response.map(|response| RegisterDeviceResponse {
+        certificate_fingerprint: fingerprint,
+        ..response
+    })
+}
+
+fn unit_error(status: OperationStatus, message: String) -> CombinedError {
+    CombinedError::ApiError(ApiError { status, message })
+}
+
+// The unit type isn't checked, the UnitType codes are unverified
+fn find_unit(data: &CuData, unit_id: i32) -> Result<&UnitItem> {
+    data.unit(unit_id).ok_or_else(|| {
+        unit_error(
+            OperationStatus::DeviceNotFound,
+            format!("no unit {}", unit_id),
+        )
+    })
+}
+
+#[cfg(feature = "experimental")]
+fn thermostat_state(item: &UnitItem) -> Result<ThermostatState> {
+    item.thermostat.clone().ok_or_else(|| {
+        unit_error(
+            OperationStatus::ERROR,
+            format!("thermostat {} reported no state", item.unit_id),
+        )
+    })
+}
+
+impl CuClient {
+    pub async fn get_all(&self) -> Result<CuData> {
+        self.execute::<GetAll>(&()).await
+    }
+    pub async fn unit_operation(&self, op: &UnitItemOperation) -> Result<CuStatus> {
+        self.execute::<UnitOperation>(op).await
+    }
+
+    // Operations read the unit first, they are sent with the type the central unit
+    // reports for it
+    async fn unit(&self, unit_id: i32) -> Result<UnitItem> {
+        let data = self.get_all().await?;
+        Ok(find_unit(&data, unit_id)?.clone())
+    }
+
+    pub async fn set_brightness(&self, unit_id: i32, level: u8) -> Result<CuStatus> {
+        let item = self.unit(unit_id).await?;
+        self.unit_operation(&UnitItemOperation::brightness(&item, level))
+            .await
+    }
+
+    pub async fn brightness(&self, unit_id: i32) -> Result<u8> {
+        Ok(self.unit(unit_id).await?.percentage())
+    }
+
+    // Moves the level by step, clamped to 0-100, and returns the new level
+    pub async fn step_brightness(&self, unit_id: i32, step: i32) -> Result<u8> {
+        let item = self.unit(unit_id).await?;
+        let level = (item.percentage() as i32 + step).clamp(0, MAX_BRIGHTNESS as i32) as u8;
+        self.unit_operation(&UnitItemOperation::brightness(&item, level))
+            .await?;
+        Ok(level)
+    }
+
+    pub async fn open_shutter(&self, unit_id: i32) -> Result<CuStatus> {
+        self.set_shutter_position(unit_id, SHUTTER_OPEN).await
+    }
+
+    pub async fn close_shutter(&self, unit_id: i32) -> Result<CuStatus> {
+        self.set_shutter_position(unit_id, SHUTTER_CLOSED).await
+    }
+
+    // There is no stop or tilt, neither has been seen in a captured exchange with a
+    // central unit yet
+    pub async fn set_shutter_position(&self, unit_id: i32, position: u8) -> Result<CuStatus> {
+        let item = self.unit(unit_id).await?;
+        self.unit_operation(&UnitItemOperation::shutter_position(&item, position))
+            .await
+    }
+
+    pub async fn shutter_position(&self, unit_id: i32) -> Result<u8> {
+        Ok(self.unit(unit_id).await?.percentage())
+    }
+
+    pub async fn start_timed_power(&self, unit_id: i32, duration: Duration) -> Result<CuStatus> {
+        // Checked before the unit is read, see UnitItemOperation::timed_power
+        timed_power_minutes(duration)?;
+        let item = self.unit(unit_id).await?;
+        self.unit_operation(&UnitItemOperation::timed_power(&item, duration)?)
+            .await
+    }
+
+    pub async fn cancel_timed_power(&self, unit_id: i32) -> Result<CuStatus> {
+        let item = self.unit(unit_id).await?;
+        self.unit_operation(&UnitItemOperation::cancel_timed_power(&item))
+            .await
+    }
+
+    pub async fn remaining_time(&self, unit_id: i32) -> Result<Duration> {
+        Ok(self.unit(unit_id).await?.minutes())
     }
-    Ok(resp)
 }
 
+// Experimental, see ThermostatState
+#[cfg(feature = "experimental")]
 impl CuClient {
-    pub async fn get_all(&mut self) -> Result<CuData> {
-        let resp = self.request("GETA").await?;
-        Ok(serde_json::from_str::<CuData>(&resp)?)
+    pub async fn thermostat(&self, unit_id: i32) -> Result<ThermostatState> {
+        thermostat_state(&self.unit(unit_id).await?)
+    }
+
+    pub async fn set_thermostat(&self, unit_id: i32, state: ThermostatState) -> Result<CuStatus> {
+        let item = self.unit(unit_id).await?;
+        self.unit_operation(&UnitItemOperation::thermostat(&item, state))
+            .await
+    }
+
+    pub async fn set_thermostat_mode(
+        &self,
+        unit_id: i32,
+        mode: ThermostatMode,
+    ) -> Result<ThermostatState> {
+        self.update_thermostat(unit_id, |state| state.mode = mode)
+            .await
     }
-    pub async fn unit_operation(&mut self, op: &UnitItemOperation) -> Result<CuStatus> {
-        let resp = self
-            .request(&("UNOP".to_string() + &serde_json::to_string(op)?.to_owned()))
+
+    pub async fn set_fan_speed(&self, unit_id: i32, fan: FanSpeed) -> Result<ThermostatState> {
+        self.update_thermostat(unit_id, |state| state.fan = fan)
+            .await
+    }
+
+    pub async fn set_target_temperature(
+        &self,
+        unit_id: i32,
+        temperature: i32,
+    ) -> Result<ThermostatState> {
+        self.update_thermostat(unit_id, |state| state.target_temperature = temperature)
+            .await
+    }
+
+    // The central unit only takes whole thermostat states, changes a single setting
+    // of the current state and returns the state that was sent
+    async fn update_thermostat<F: FnOnce(&mut ThermostatState)>(
+        &self,
+        unit_id: i32,
+        update: F,
+    ) -> Result<ThermostatState> {
+        let item = self.unit(unit_id).await?;
+        let mut state = thermostat_state(&item)?;
+        update(&mut state);
+        self.unit_operation(&UnitItemOperation::thermostat(&item, state.clone()))
             .await?;
-        let resp: CuStatus = serde_json::from_str(&resp)?;
-        if resp.status != OperationStatus::OK {
-            return Err(CombinedError::ApiError(ApiError {
-                message: resp.status.to_string(),
-                status: resp.status,
-                is_wrong_message_id: false,
-            }));
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::default_cu_data;
+
+    #[test]
+    fn discovery_skips_invalid_responses() {
+        runtime::block_on(async {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let address = socket.local_addr().unwrap();
+            let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let valid = serde_json::to_string(&default_cu_data()).unwrap();
+            let packets: [&[u8]; 6] = [
+                b"",
+                &[0xff, 0xfe, 0x00, 0x80],
+                b"FIND",
+                b"{\"mac\": ",
+                b"{\"mac\": \"00:00:5e:00:53:02\"}",
+                valid.as_bytes(),
+            ];
+            for packet in packets {
+                sender.send_to(packet, address).await.unwrap();
+            }
+            let units = collect_responses(socket, DISCOVERY_TIMEOUT, |_| true)
+                .await
+                .unwrap();
+            assert_eq!(units.len(), 1);
+            assert_eq!(units[0].mac, default_cu_data().mac);
+            assert_eq!(units[0].CUIP, "127.0.0.1");
+        });
+    }
+
+    #[test]
+    fn unexpected_thermostat_values_are_kept() {
+        let item: UnitItem = serde_json::from_str(
+            r#"{
+                "name": "Air conditioner",
+                "unitId": 6,
+                "value": 0,
+                "type": 5,
+                "thermostat": {"mode": "OFF", "fan": "auto", "targetTemperature": 24}
+            }"#,
+        )
+        .unwrap();
+        let state = item.thermostat.unwrap();
+        assert_eq!(state.mode, ThermostatMode::Unknown("OFF".to_string()));
+        assert_eq!(state.fan, FanSpeed::Auto);
+
+        let json = serde_json::to_value(&state).unwrap();
+        assert_eq!(json["mode"], "OFF");
+        assert_eq!(json["fan"], "AUTO");
+    }
+
+    #[test]
+    fn operations_keep_the_reported_unit_type() {
+        let item: UnitItem =
+            serde_json::from_str(r#"{"name": "Kitchen", "unitId": 9, "value": 30, "type": 42}"#)
+                .unwrap();
+        let op = UnitItemOperation::brightness(&item, 120);
+        assert_eq!(op.unit_type, UnitType::Unknown(42));
+        assert_eq!(op.new_state, MAX_BRIGHTNESS as i32);
+        let json = serde_json::to_value(&op).unwrap();
+        assert_eq!(json["type"], 42);
+
+        assert_eq!("42".parse::<UnitType>().unwrap().code(), 42);
+        assert!("dimmer".parse::<UnitType>().is_err());
+    }
+
+    #[test]
+    fn timed_power_takes_whole_minutes() {
+        let item: UnitItem =
+            serde_json::from_str(r#"{"name": "Water heater", "unitId": 7, "value": 0, "type": 4}"#)
+                .unwrap();
+        let op = UnitItemOperation::timed_power(&item, Duration::from_secs(90 * 60)).unwrap();
+        assert_eq!(op.new_state, 90);
+        assert_eq!(op.unit_type, item.unit_type);
+        assert_eq!(UnitItemOperation::cancel_timed_power(&item).new_state, 0);
+
+        for duration in [
+            Duration::ZERO,
+            Duration::from_secs(30),
+            Duration::from_secs(61),
+            Duration::from_millis(60_500),
+            Duration::from_secs(60 * (i32::MAX as u64 + 1)),
+        ] {
+            let err = UnitItemOperation::timed_power(&item, duration)
+                .err()
+                .unwrap();
+            assert!(
+                matches!(err.root(), CombinedError::ApiError(_)),
+                "{:?}",
+                duration
+            );
         }
-        Ok(resp)
+    }
+
+    fn identity_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("bswitch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn device_identity_round_trip() {
+        let path = identity_path("round-trip.p12");
+        let (key, cert) = crate::keygen::generate_keypair("test@localhost", "Test").unwrap();
+        runtime::block_on(async {
+            save_device_identity(&path, &key, &cert, "secret")
+                .await
+                .unwrap();
+            load_device_identity(&path, "secret").await.unwrap();
+        });
+        let (saved_key, chain) =
+            crate::keygen::parse_pkcs12(&std::fs::read(&path).unwrap(), "secret").unwrap();
+        assert_eq!(saved_key, key);
+        assert_eq!(chain, vec![cert]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn device_identity_with_wrong_password_fails() {
+        let path = identity_path("wrong-password.p12");
+        let (key, cert) = crate::keygen::generate_keypair("test@localhost", "Test").unwrap();
+        runtime::block_on(async {
+            save_device_identity(&path, &key, &cert, "secret")
+                .await
+                .unwrap();
+            let err = load_device_identity(&path, "wrong").await.err().unwrap();
+            assert!(err.to_string().contains(&path));
+        });
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn identity_from_env_requires_the_password() {
+        std::env::set_var(IDENTITY_ENV, "MAA=");
+        let result = identity_from_env();
+        std::env::remove_var(IDENTITY_ENV);
+        let err = result.err().unwrap();
+        assert!(err.to_string().contains(IDENTITY_PASSWORD_ENV));
     }
 }
@@ -10,6 +10,28 @@ pub enum BksError {
     SignatureError(KeystoreSignatureError),
 }
 
+impl Display for BksError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BksError::IoError(e) => f.write_fmt(format_args!("Failed to read keystore: {}", e)),
+            BksError::FormatError(e) => f.write_fmt(format_args!("Invalid keystore: {}", e)),
+            BksError::Utf8Error(e) => f.write_fmt(format_args!("Invalid keystore string: {}", e)),
+            BksError::SignatureError(e) => Display::fmt(e, f),
+        }
+    }
+}
+
+impl Error for BksError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BksError::IoError(e) => Some(e),
+            BksError::FormatError(e) => Some(e),
+            BksError::Utf8Error(e) => Some(e),
+            BksError::SignatureError(e) => Some(e),
+        }
+    }
+}
+
 impl From<std::io::Error> for BksError {
     fn from(e: std::io::Error) -> Self {
         Self::IoError(e)
@@ -89,3 +111,5 @@ impl KeystoreSignatureError {
         }
     }
 }
+
+impl Error for KeystoreSignatureError {}
@@ -1,14 +1,14 @@
-use async_std::io::{BufReader, Read};
-use async_std::prelude::*;
 use des::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
 use digest::core_api::BlockSizeUser;
 use encoding::all::UTF_16BE;
 use encoding::{EncoderTrap, Encoding};
+use futures::io::{AsyncRead as Read, AsyncReadExt, BufReader};
 use hmac::{Hmac, Mac};
 use sha1::{Digest, Sha1};
 use std::collections::HashMap;
 use std::pin::Pin;
 use std::task::Poll;
+use tracing::{debug, instrument, warn};
 
 use crate::bks::errors;
 
@@ -178,7 +178,7 @@ impl BksEntry {
                     &entry.salt,
                     entry.iteration_count,
                     64 / 8,
-                ));
+                )?);
                 let mut key: [u8; 24] = [0; 24];
                 key.copy_from_slice(&rfc7292_derieve_key::<Sha1>(
                     1,
@@ -186,10 +186,14 @@ impl BksEntry {
                     &entry.salt,
                     entry.iteration_count,
                     192 / 8,
-                ));
+                )?);
                 let mut pt = Des3EdeCBC::new(&key.into(), &iv.into())
                     .decrypt_padded_mut::<Pkcs7>(&mut entry.sealed_data)
-                    .unwrap();
+                    .map_err(|_| {
+                        errors::BksFormatError::new(
+                            "bad padding in sealed entry, wrong password?".to_string(),
+                        )
+                    })?;
                 BksEntryValue::KeyEntry(BksKeyEntry::load(&mut pt).await?)
             }
             _ => {
@@ -231,11 +235,13 @@ pub struct BksKeyStore {
 }
 
 impl BksKeyStore {
+    #[instrument(level = "debug", skip_all)]
     pub async fn load<T>(reader: &mut T, password: &str) -> Result<BksKeyStore, errors::BksError>
     where
         T: Read + Unpin,
     {
         let version = read_u32(reader).await?;
+        debug!(version, "parsing keystore");
         let store_type = "bks".to_string();
         if version != 1 && version != 2 {
             return Err(errors::BksFormatError::new(
@@ -256,17 +262,19 @@ impl BksKeyStore {
             password,
             &salt,
             iteration_count,
-            (hmac_key_size / 8).try_into().unwrap(),
-        );
+            (hmac_key_size / 8) as u32,
+        )?;
         let (entries, calculated_hmac) =
             read_bks_entries_hmac(reader, &hmac_key, &password).await?;
         let mut store_hmac = vec![0; Sha1::output_size()];
         reader.read_exact(&mut store_hmac).await?;
         if store_hmac != calculated_hmac {
+            warn!("keystore signature mismatch, wrong password or corrupted keystore");
             return Err(errors::BksError::SignatureError(
                 errors::KeystoreSignatureError::new(store_hmac, calculated_hmac),
             ));
         }
+        debug!(entries = entries.len(), "keystore loaded");
         Ok(BksKeyStore {
             version,
             store_type,
@@ -345,11 +353,8 @@ where
 {
     let mut size_buf = [0; 4];
     reader.read_exact(&mut size_buf).await?;
-    let size: usize = u32::from_be_bytes(size_buf).try_into().unwrap();
-    let mut buffer = Vec::with_capacity(size);
-    unsafe { buffer.set_len(size) }
-    reader.read_exact(&mut buffer).await?;
-    Ok(buffer)
+    let size = u32::from_be_bytes(size_buf) as usize;
+    read_exact_vec(reader, size).await
 }
 
 async fn read_utf8<T>(reader: &mut T) -> Result<String, errors::BksError>
@@ -358,12 +363,22 @@ where
 {
     let mut size_buf = [0; 2];
     reader.read_exact(&mut size_buf).await?;
-    let size: usize = u16::from_be_bytes(size_buf).try_into().unwrap();
-    // Skip 2 bytes
-    let mut buffer = Vec::with_capacity(size);
-    unsafe { buffer.set_len(size) }
-    reader.read_exact(&mut buffer).await?;
-    Ok(String::from_utf8(buffer)?)
+    let size = u16::from_be_bytes(size_buf) as usize;
+    Ok(String::from_utf8(read_exact_vec(reader, size).await?)?)
+}
+
+// Sizes come from the keystore, the buffer only grows as data is actually read so a
+// corrupted size fails with an EOF instead of a huge allocation
+async fn read_exact_vec<T>(reader: &mut T, size: usize) -> Result<Vec<u8>, errors::BksError>
+where
+    T: Read + Unpin,
+{
+    let mut buffer = Vec::new();
+    reader.take(size as u64).read_to_end(&mut buffer).await?;
+    if buffer.len() != size {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(buffer)
 }
 
 async fn read_bks_entries<T>(
@@ -387,12 +402,12 @@ where
 
 fn _adjust(a: &mut [u8], a_offset: usize, b: &[u8]) {
     let mut x: u32 = (*b.last().unwrap() as u32) + (a[a_offset + b.len() - 1] as u32) + 1;
-    a[a_offset + b.len() - 1] = (x & 0xff).try_into().unwrap();
+    a[a_offset + b.len() - 1] = (x & 0xff) as u8;
     x >>= 8;
 
     for i in (0..(b.len() - 1)).rev() {
         x += (b[i] as u32) + (a[a_offset + i] as u32);
-        a[a_offset + i] = (x & 0xff).try_into().unwrap();
+        a[a_offset + i] = (x & 0xff) as u8;
         x >>= 8;
     }
 }
@@ -403,10 +418,10 @@ fn rfc7292_derieve_key<T: Digest + BlockSizeUser>(
     salt: &[u8],
     iteration_count: u32,
     key_size: u32,
-) -> Vec<u8> {
+) -> Result<Vec<u8>, errors::BksError> {
     let mut password_bytes = UTF_16BE
-        .encode(&password.to_string(), EncoderTrap::Strict)
-        .unwrap();
+        .encode(password, EncoderTrap::Strict)
+        .map_err(|e| errors::BksFormatError::new(format!("bad password: {}", e)))?;
     password_bytes.extend([0, 0].iter());
     let u = <T as Digest>::output_size() as u32;
     let v = <T as BlockSizeUser>::block_size();
@@ -442,7 +457,7 @@ fn rfc7292_derieve_key<T: Digest + BlockSizeUser>(
     }
 
     derived_key.resize(key_size as usize, 0);
-    derived_key
+    Ok(derived_key)
 }
 
 async fn read_bks_entries_hmac<T>(
@@ -455,10 +470,154 @@ where
 {
     let mut hmac_reader = HMACReader {
         inner: reader,
-        hmac: Hmac::<Sha1>::new_from_slice(key).unwrap(),
+        hmac: Hmac::<Sha1>::new_from_slice(key)
+            .map_err(|_| errors::BksFormatError::new("bad hmac key".to_string()))?,
     };
     Ok((
         read_bks_entries(&mut hmac_reader, password).await?,
         hmac_reader.hmac.finalize().into_bytes().to_vec(),
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use des::cipher::BlockEncryptMut;
+    use futures::executor::block_on;
+
+    type Des3EdeCBCEncryptor = cbc::Encryptor<des::TdesEde3>;
+
+    const PASSWORD: &str = "keystore password";
+    const KEY: &[u8] = b"private key bytes";
+
+    fn data(bytes: &[u8]) -> Vec<u8> {
+        let mut result = (bytes.len() as u32).to_be_bytes().to_vec();
+        result.extend_from_slice(bytes);
+        result
+    }
+
+    fn utf8(s: &str) -> Vec<u8> {
+        let mut result = (s.len() as u16).to_be_bytes().to_vec();
+        result.extend_from_slice(s.as_bytes());
+        result
+    }
+
+    fn sealed_entry(alias: &str, password: &str) -> Vec<u8> {
+        let salt = [7; 20];
+        let iteration_count = 3;
+        let mut key_entry = vec![2];
+        key_entry.extend(utf8("PKCS#8"));
+        key_entry.extend(utf8("RSA"));
+        key_entry.extend(data(KEY));
+
+        let key = rfc7292_derieve_key::<Sha1>(1, password, &salt, iteration_count, 24).unwrap();
+        let iv = rfc7292_derieve_key::<Sha1>(2, password, &salt, iteration_count, 8).unwrap();
+        let size = key_entry.len();
+        key_entry.resize(size + 8, 0);
+        let encrypted = Des3EdeCBCEncryptor::new(key.as_slice().into(), iv.as_slice().into())
+            .encrypt_padded_mut::<Pkcs7>(&mut key_entry, size)
+            .unwrap()
+            .to_vec();
+
+        let mut sealed = data(&salt);
+        sealed.extend(iteration_count.to_be_bytes());
+        sealed.extend(encrypted);
+
+        let mut entry = vec![4];
+        entry.extend(utf8(alias));
+        entry.extend(0u64.to_be_bytes());
+        entry.extend(0u32.to_be_bytes());
+        entry.extend(data(&sealed));
+        entry
+    }
+
+    // Version 2 keystore holding one sealed key entry
+    fn keystore(password: &str) -> Vec<u8> {
+        let salt = [3; 20];
+        let iteration_count = 2;
+        let mut entries = sealed_entry("device", password);
+        entries.push(0);
+        let hmac_key =
+            rfc7292_derieve_key::<Sha1>(3, password, &salt, iteration_count, 20).unwrap();
+        let mut hmac = Hmac::<Sha1>::new_from_slice(&hmac_key).unwrap();
+        hmac.update(&entries);
+
+        let mut store = 2u32.to_be_bytes().to_vec();
+        store.extend(data(&salt));
+        store.extend(iteration_count.to_be_bytes());
+        store.extend(entries);
+        store.extend(hmac.finalize().into_bytes());
+        store
+    }
+
+    fn load(store: &[u8], password: &str) -> Result<BksKeyStore, errors::BksError> {
+        block_on(BksKeyStore::load(&mut &store[..], password))
+    }
+
+    #[test]
+    fn loads_sealed_key_entry() {
+        let store = load(&keystore(PASSWORD), PASSWORD).unwrap();
+        assert_eq!(store.version(), 2);
+        match store.entries()["device"].value() {
+            BksEntryValue::KeyEntry(key) => {
+                assert_eq!(key.key_format(), "PKCS#8");
+                assert_eq!(key.key_algorithm(), "RSA");
+                assert_eq!(key.data(), KEY);
+            }
+            value => panic!("expected a key entry, got {:?}", value),
+        }
+    }
+
+    #[test]
+    fn wrong_password_fails_on_sealed_entry_padding() {
+        match load(&keystore(PASSWORD), "wrong password") {
+            Err(errors::BksError::FormatError(e)) => {
+                assert!(e.to_string().contains("bad padding"), "{}", e)
+            }
+            result => panic!("expected a padding error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn truncated_keystores_are_rejected() {
+        let store = keystore(PASSWORD);
+        for size in 0..store.len() {
+            assert!(load(&store[..size], PASSWORD).is_err(), "size {}", size);
+        }
+    }
+
+    #[test]
+    fn oversized_length_prefix_is_rejected() {
+        let mut store = 2u32.to_be_bytes().to_vec();
+        store.extend(u32::MAX.to_be_bytes());
+        store.extend([0; 16]);
+        match load(&store, PASSWORD) {
+            Err(errors::BksError::IoError(e)) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof)
+            }
+            result => panic!("expected an EOF error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn unsupported_version_is_rejected() {
+        let mut store = keystore(PASSWORD);
+        store[..4].copy_from_slice(&3u32.to_be_bytes());
+        assert!(matches!(
+            load(&store, PASSWORD),
+            Err(errors::BksError::FormatError(_))
+        ));
+    }
+
+    #[test]
+    fn corrupted_entries_fail_the_signature() {
+        let mut store = keystore(PASSWORD);
+        // Last byte of the entry timestamp, covered by the HMAC but not decrypted
+        let offset = 4 + 4 + 20 + 4 + 1 + 2 + "device".len() + 7;
+        store[offset] ^= 1;
+        assert!(matches!(
+            load(&store, PASSWORD),
+            Err(errors::BksError::SignatureError(_))
+        ));
+    }
+}
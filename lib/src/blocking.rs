@@ -0,0 +1,208 @@
+// Synchronous facade over the async API for scripts that don't run an executor, every
+// call blocks the current thread until the underlying future completes
+use futures::StreamExt;
+use std::future::Future;
+use std::io::Read;
+use std::time::Duration;
+
+use crate::api::{
+    self, Command, CuData, CuStatus, RegisterDeviceParams, RegisterDeviceResponse, Result,
+    UnitItemOperation,
+};
+#[cfg(feature = "experimental")]
+use crate::api::{FanSpeed, ThermostatMode, ThermostatState};
+use crate::bks::errors::BksError;
+use crate::bks::keystore;
+use crate::codec::MessageWrapper;
+use crate::protocol::{self, CuClientOptions, Notification};
+use crate::runtime;
+use crate::tls::Identity;
+
+// Fails when called from async code, see runtime::try_block_on
+fn block_on<T>(future: impl Future<Output = Result<T>>) -> Result<T> {
+    runtime::try_block_on(future)?
+}
+
+pub struct CuClient {
+    inner: protocol::CuClient,
+}
+
+impl CuClient {
+    pub fn new(ip: &str, port: u32, identity: Identity) -> Result<Self> {
+        Self::with_options(ip, port, identity, CuClientOptions::default())
+    }
+
+    pub fn with_options(
+        ip: &str,
+        port: u32,
+        identity: Identity,
+        options: CuClientOptions,
+    ) -> Result<Self> {
+        Ok(CuClient {
+            inner: block_on(protocol::CuClient::with_options(
+                ip, port, identity, options,
+            ))?,
+        })
+    }
+
+    pub fn request(&self, request: &str) -> Result<String> {
+        block_on(self.inner.request(request))
+    }
+
+    pub fn request_with_priority(&self, request: &str, priority: u8) -> Result<MessageWrapper> {
+        block_on(self.inner.request_with_priority(request, priority))
+    }
+
+    pub fn execute<C: Command>(&self, request: &C::Request) -> Result<C::Response> {
+        block_on(self.inner.execute::<C>(request))
+    }
+
+    pub fn execute_with_priority<C: Command>(
+        &self,
+        request: &C::Request,
+        priority: u8,
+    ) -> Result<C::Response> {
+        block_on(self.inner.execute_with_priority::<C>(request, priority))
+    }
+
+    pub fn get_all(&self) -> Result<CuData> {
+        block_on(self.inner.get_all())
+    }
+
+    pub fn unit_operation(&self, op: &UnitItemOperation) -> Result<CuStatus> {
+        block_on(self.inner.unit_operation(op))
+    }
+
+    pub fn set_brightness(&self, unit_id: i32, level: u8) -> Result<CuStatus> {
+        block_on(self.inner.set_brightness(unit_id, level))
+    }
+
+    pub fn brightness(&self, unit_id: i32) -> Result<u8> {
+        block_on(self.inner.brightness(unit_id))
+    }
+
+    pub fn step_brightness(&self, unit_id: i32, step: i32) -> Result<u8> {
+        block_on(self.inner.step_brightness(unit_id, step))
+    }
+
+    pub fn open_shutter(&self, unit_id: i32) -> Result<CuStatus> {
+        block_on(self.inner.open_shutter(unit_id))
+    }
+
+    pub fn close_shutter(&self, unit_id: i32) -> Result<CuStatus> {
+        block_on(self.inner.close_shutter(unit_id))
+    }
+
+    pub fn set_shutter_position(&self, unit_id: i32, position: u8) -> Result<CuStatus> {
+        block_on(self.inner.set_shutter_position(unit_id, position))
+    }
+
+    pub fn shutter_position(&self, unit_id: i32) -> Result<u8> {
+        block_on(self.inner.shutter_position(unit_id))
+    }
+
+    pub fn start_timed_power(&self, unit_id: i32, duration: Duration) -> Result<CuStatus> {
+        block_on(self.inner.start_timed_power(unit_id, duration))
+    }
+
+    pub fn cancel_timed_power(&self, unit_id: i32) -> Result<CuStatus> {
+        block_on(self.inner.cancel_timed_power(unit_id))
+    }
+
+    pub fn remaining_time(&self, unit_id: i32) -> Result<Duration> {
+        block_on(self.inner.remaining_time(unit_id))
+    }
+
+    // Blocks on every item, the iterator ends when the connection closes
+    pub fn notifications(&self) -> Result<impl Iterator<Item = Notification>> {
+        // Checked once here so a failure isn't mistaken for the end of the stream
+        runtime::try_block_on(async {})?;
+        let mut notifications = Box::pin(self.inner.notifications());
+        Ok(std::iter::from_fn(move || {
+            runtime::try_block_on(notifications.next()).ok().flatten()
+        }))
+    }
+}
+
+// Experimental, see api::ThermostatState
+#[cfg(feature = "experimental")]
+impl CuClient {
+    pub fn thermostat(&self, unit_id: i32) -> Result<ThermostatState> {
+        block_on(self.inner.thermostat(unit_id))
+    }
+
+    pub fn set_thermostat(&self, unit_id: i32, state: ThermostatState) -> Result<CuStatus> {
+        block_on(self.inner.set_thermostat(unit_id, state))
+    }
+
+    pub fn set_thermostat_mode(
+        &self,
+        unit_id: i32,
+        mode: ThermostatMode,
+    ) -> Result<ThermostatState> {
+        block_on(self.inner.set_thermostat_mode(unit_id, mode))
+    }
+
+    pub fn set_fan_speed(&self, unit_id: i32, fan: FanSpeed) -> Result<ThermostatState> {
+        block_on(self.inner.set_fan_speed(unit_id, fan))
+    }
+
+    pub fn set_target_temperature(
+        &self,
+        unit_id: i32,
+        temperature: i32,
+    ) -> Result<ThermostatState> {
+        block_on(self.inner.set_target_temperature(unit_id, temperature))
+    }
+}
+
+pub fn discover_central_units(exit_on_first: bool) -> Result<Vec<CuData>> {
+    block_on(api::discover_central_units(exit_on_first))
+}
+
+pub fn discover_central_units_at(address: &str, exit_on_first: bool) -> Result<Vec<CuData>> {
+    block_on(api::discover_central_units_at(address, exit_on_first))
+}
+
+pub fn get_default_https_client() -> Result<reqwest::Client> {
+    block_on(api::get_default_https_client())
+}
+
+pub fn get_device_identity(path: &str) -> Result<Identity> {
+    block_on(api::get_device_identity(path))
+}
+
+pub fn load_device_identity(path: &str, password: &str) -> Result<Identity> {
+    block_on(api::load_device_identity(path, password))
+}
+
+pub fn register_device(
+    client: &reqwest::Client,
+    ip: &str,
+    params: &RegisterDeviceParams,
+) -> Result<RegisterDeviceResponse> {
+    block_on(api::register_device(client, ip, params))
+}
+
+pub fn register_device_at(
+    client: &reqwest::Client,
+    ip: &str,
+    port: u16,
+    params: &RegisterDeviceParams,
+) -> Result<RegisterDeviceResponse> {
+    block_on(api::register_device_at(client, ip, port, params))
+}
+
+pub struct BksKeyStore;
+
+impl BksKeyStore {
+    // The keystore is small, it is read into memory and parsed from there
+    pub fn load<T: Read>(
+        reader: &mut T,
+        password: &str,
+    ) -> std::result::Result<keystore::BksKeyStore, BksError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        runtime::try_block_on(keystore::BksKeyStore::load(&mut data.as_slice(), password))?
+    }
+}
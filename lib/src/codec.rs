@@ -0,0 +1,361 @@
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::{AsyncReadExt, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str;
+
+use crate::api::*;
+
+pub const FRAME_MAGIC: [u8; 4] = [127, 54, 60, 162];
+
+#[derive(Debug)]
+pub enum FramingError {
+    BadMagic([u8; 4]),
+    FrameTooLarge { size: usize, max_size: usize },
+}
+
+impl fmt::Display for FramingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FramingError::BadMagic(magic) => f.write_fmt(format_args!(
+                "Bad frame magic {:?}, expected {:?}",
+                magic, FRAME_MAGIC
+            )),
+            FramingError::FrameTooLarge { size, max_size } => f.write_fmt(format_args!(
+                "Frame of {} bytes exceeds the maximum of {} bytes",
+                size, max_size
+            )),
+        }
+    }
+}
+
+impl std::error::Error for FramingError {}
+
+pub const HEADER_SIZE: usize = 6;
+
+#[derive(Debug)]
+pub enum DecodeError {
+    TruncatedHeader(usize),
+    UnknownMessageType(u8),
+    InvalidUtf8(str::Utf8Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TruncatedHeader(size) => f.write_fmt(format_args!(
+                "Message of {} bytes is shorter than the {} byte header",
+                size, HEADER_SIZE
+            )),
+            DecodeError::UnknownMessageType(message_type) => {
+                f.write_fmt(format_args!("Unknown message type {}", message_type))
+            }
+            DecodeError::InvalidUtf8(err) => {
+                f.write_fmt(format_args!("Message is not valid UTF-8: {}", err))
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::InvalidUtf8(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageType {
+    Request = 1,
+    Response = 2,
+    Notification = 3,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        match value {
+            x if x == MessageType::Request as u8 => Ok(MessageType::Request),
+            x if x == MessageType::Response as u8 => Ok(MessageType::Response),
+            x if x == MessageType::Notification as u8 => Ok(MessageType::Notification),
+            _ => Err(DecodeError::UnknownMessageType(value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageWrapper {
+    pub message_type: MessageType,
+    pub priority: u8,
+    pub message_id: u32,
+    pub message: String,
+}
+
+impl MessageWrapper {
+    pub fn new(message_type: MessageType, message_id: u32, message: &str) -> MessageWrapper {
+        MessageWrapper {
+            message_type,
+            priority: 0,
+            message_id,
+            message: message.to_owned(),
+        }
+    }
+
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result: Vec<u8> = Vec::<u8>::with_capacity(HEADER_SIZE + self.message.len());
+        result.push(self.message_type as u8);
+        result.push(self.priority);
+        result.extend_from_slice(&self.message_id.to_le_bytes());
+        result.extend(self.message.as_bytes());
+        result
+    }
+
+    pub fn deserialize(data: &[u8]) -> std::result::Result<MessageWrapper, DecodeError> {
+        if data.len() < HEADER_SIZE {
+            return Err(DecodeError::TruncatedHeader(data.len()));
+        }
+        let (header, body) = data.split_at(HEADER_SIZE);
+        Ok(MessageWrapper {
+            message_type: header[0].try_into()?,
+            priority: header[1],
+            message_id: u32::from_le_bytes([header[2], header[3], header[4], header[5]]),
+            message: str::from_utf8(body)
+                .map_err(DecodeError::InvalidUtf8)?
+                .to_string(),
+        })
+    }
+}
+
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut result = Vec::<u8>::with_capacity(8 + payload.len());
+    result.extend_from_slice(&FRAME_MAGIC);
+    result.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    result.extend(payload);
+    result
+}
+
+// Reads length prefixed frames from any byte stream
+pub struct FrameReader<R> {
+    inner: R,
+    max_frame_size: usize,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(inner: R, max_frame_size: usize) -> Self {
+        FrameReader {
+            inner,
+            max_frame_size,
+        }
+    }
+
+    pub async fn read_frame(&mut self) -> Result<Vec<u8>> {
+        let mut magic = [0; 4];
+        self.inner.read_exact(&mut magic).await?;
+        if magic != FRAME_MAGIC {
+            return Err(FramingError::BadMagic(magic).into());
+        }
+
+        let mut size = [0; 4];
+        self.inner.read_exact(&mut size).await?;
+        let size = u32::from_le_bytes(size) as usize;
+        if size > self.max_frame_size {
+            return Err(FramingError::FrameTooLarge {
+                size,
+                max_size: self.max_frame_size,
+            }
+            .into());
+        }
+
+        let mut buffer = vec![0; size];
+        self.inner.read_exact(&mut buffer).await?;
+        Ok(buffer)
+    }
+
+    // A DecodeError leaves the reader at the start of the next frame
+    pub async fn read_message(&mut self) -> Result<MessageWrapper> {
+        let frame = self.read_frame().await?;
+        Ok(MessageWrapper::deserialize(&frame)?)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+// Writes length prefixed frames to any byte stream
+pub struct FrameWriter<W> {
+    inner: W,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    pub fn new(inner: W) -> Self {
+        FrameWriter { inner }
+    }
+
+    pub async fn write_frame(&mut self, payload: &[u8]) -> Result<()> {
+        self.inner.write_all(&encode_frame(payload)).await?;
+        self.inner.flush().await?;
+        Ok(())
+    }
+
+    pub async fn write_message(&mut self, message: &MessageWrapper) -> Result<()> {
+        self.write_frame(&message.serialize()).await
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use proptest::prelude::*;
+
+    const MAX_FRAME_SIZE: usize = 1024;
+
+    fn read_frame(data: &[u8]) -> Result<Vec<u8>> {
+        block_on(FrameReader::new(data, MAX_FRAME_SIZE).read_frame())
+    }
+
+    fn read_message(data: &[u8]) -> Result<MessageWrapper> {
+        block_on(FrameReader::new(data, MAX_FRAME_SIZE).read_message())
+    }
+
+    fn message_type() -> impl Strategy<Value = MessageType> {
+        prop_oneof![
+            Just(MessageType::Request),
+            Just(MessageType::Response),
+            Just(MessageType::Notification),
+        ]
+    }
+
+    fn message() -> impl Strategy<Value = MessageWrapper> {
+        (message_type(), any::<u8>(), any::<u32>(), ".{0,64}").prop_map(
+            |(message_type, priority, message_id, message)| {
+                MessageWrapper::new(message_type, message_id, &message).with_priority(priority)
+            },
+        )
+    }
+
+    fn assert_same(decoded: &MessageWrapper, message: &MessageWrapper) {
+        assert_eq!(decoded.message_type, message.message_type);
+        assert_eq!(decoded.priority, message.priority);
+        assert_eq!(decoded.message_id, message.message_id);
+        assert_eq!(decoded.message, message.message);
+    }
+
+    proptest! {
+        #[test]
+        fn deserialize_never_panics(data in proptest::collection::vec(any::<u8>(), 0..64)) {
+            let _ = MessageWrapper::deserialize(&data);
+        }
+
+        #[test]
+        fn read_message_never_panics(data in proptest::collection::vec(any::<u8>(), 0..64)) {
+            let _ = read_message(&data);
+        }
+
+        #[test]
+        fn read_message_never_panics_after_magic(
+            data in proptest::collection::vec(any::<u8>(), 0..64)
+        ) {
+            let mut frame = FRAME_MAGIC.to_vec();
+            frame.extend(data);
+            let _ = read_message(&frame);
+        }
+
+        #[test]
+        fn short_messages_are_truncated(data in proptest::collection::vec(any::<u8>(), 0..HEADER_SIZE)) {
+            prop_assert!(matches!(
+                MessageWrapper::deserialize(&data),
+                Err(DecodeError::TruncatedHeader(size)) if size == data.len()
+            ));
+        }
+
+        #[test]
+        fn unknown_message_types_are_rejected(
+            message_type in 4u8..,
+            data in proptest::collection::vec(any::<u8>(), HEADER_SIZE - 1..32),
+        ) {
+            let mut message = vec![message_type];
+            message.extend(data);
+            prop_assert!(matches!(
+                MessageWrapper::deserialize(&message),
+                Err(DecodeError::UnknownMessageType(t)) if t == message_type
+            ));
+        }
+
+        #[test]
+        fn messages_round_trip(message in message()) {
+            let decoded = MessageWrapper::deserialize(&message.serialize()).unwrap();
+            assert_same(&decoded, &message);
+        }
+
+        #[test]
+        fn frames_round_trip(message in message()) {
+            let decoded = read_message(&encode_frame(&message.serialize())).unwrap();
+            assert_same(&decoded, &message);
+        }
+
+        #[test]
+        fn bad_magic_is_rejected(
+            magic in any::<[u8; 4]>().prop_filter("valid magic", |magic| *magic != FRAME_MAGIC),
+            data in proptest::collection::vec(any::<u8>(), 4..32),
+        ) {
+            let mut frame = magic.to_vec();
+            frame.extend(data);
+            prop_assert!(matches!(
+                read_frame(&frame),
+                Err(CombinedError::FramingError(FramingError::BadMagic(m))) if m == magic
+            ));
+        }
+
+        #[test]
+        fn oversized_frames_are_rejected(size in MAX_FRAME_SIZE as u32 + 1..) {
+            let mut frame = FRAME_MAGIC.to_vec();
+            frame.extend(size.to_le_bytes());
+            let rejected = matches!(
+                read_frame(&frame),
+                Err(CombinedError::FramingError(FramingError::FrameTooLarge { size: s, .. }))
+                    if s == size as usize
+            );
+            prop_assert!(rejected);
+        }
+
+        #[test]
+        fn truncated_frames_are_rejected(message in message(), cut in any::<prop::sample::Index>()) {
+            let frame = encode_frame(&message.serialize());
+            let frame = &frame[..cut.index(frame.len())];
+            prop_assert!(matches!(
+                read_frame(frame),
+                Err(CombinedError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+            ));
+        }
+    }
+
+    #[test]
+    fn undecodable_frame_keeps_the_stream_in_sync() {
+        let message = MessageWrapper::new(MessageType::Notification, 7, "{}");
+        let mut data = encode_frame(&[9, 0, 0, 0, 0, 0]);
+        data.extend(encode_frame(&message.serialize()));
+        let mut reader = FrameReader::new(data.as_slice(), MAX_FRAME_SIZE);
+        assert!(matches!(
+            block_on(reader.read_message()),
+            Err(CombinedError::DecodeError(DecodeError::UnknownMessageType(
+                9
+            )))
+        ));
+        assert_same(&block_on(reader.read_message()).unwrap(), &message);
+    }
+}
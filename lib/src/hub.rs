@@ -0,0 +1,212 @@
+use futures::future;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::api::*;
+use crate::protocol::CuClientOptions;
+use crate::reconnect::{ReconnectPolicy, ResilientCuClient};
+use crate::runtime;
+use crate::tls::Identity;
+
+struct Hub {
+    name: String,
+    client: Arc<ResilientCuClient>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HubInfo {
+    pub mac: String,
+    pub name: String,
+    pub ip: String,
+}
+
+// A unit together with the central unit it is attached to
+#[derive(Debug, Clone)]
+pub struct HubUnit {
+    pub hub_mac: String,
+    pub hub_name: String,
+    pub zone: String,
+    pub item: UnitItem,
+}
+
+impl HubUnit {
+    pub fn operation(&self, new_state: i32) -> UnitItemOperation {
+        UnitItemOperation::new(self.item.unit_id, self.item.unit_type, new_state)
+    }
+}
+
+// Units of all the reachable central units, hubs that failed are listed with their
+// error instead of failing the whole inventory
+#[derive(Debug)]
+pub struct Inventory {
+    pub units: Vec<HubUnit>,
+    pub errors: Vec<(String, CombinedError)>,
+}
+
+// Time get_all waits for a single central unit, including its reconnect attempts
+pub const DEFAULT_HUB_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Manages the connections to several central units, each with its own identity,
+// keyed by the MAC address reported in CuData
+pub struct HubManager {
+    policy: ReconnectPolicy,
+    // Used by add, hubs with their own pinned certificate are added with add_with_options
+    options: CuClientOptions,
+    timeout: Duration,
+    hubs: Mutex<HashMap<String, Hub>>,
+}
+
+fn unknown_hub(key: &str) -> CombinedError {
+    CombinedError::ApiError(ApiError {
+        status: OperationStatus::DeviceNotFound,
+        message: format!("no central unit {}", key),
+    })
+}
+
+impl Default for HubManager {
+    fn default() -> Self {
+        Self::new(ReconnectPolicy::default(), CuClientOptions::default())
+    }
+}
+
+impl HubManager {
+    pub fn new(policy: ReconnectPolicy, options: CuClientOptions) -> Self {
+        HubManager {
+            policy,
+            options,
+            timeout: DEFAULT_HUB_TIMEOUT,
+            hubs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Connects to a central unit and returns its MAC, a central unit that was already
+    // added is replaced
+    pub async fn add(&self, ip: &str, port: u32, identity: Identity) -> Result<String> {
+        self.add_with_options(ip, port, identity, self.options.clone())
+            .await
+    }
+
+    // Like add with options for this central unit only, such as its pinned certificate
+    pub async fn add_with_options(
+        &self,
+        ip: &str,
+        port: u32,
+        identity: Identity,
+        options: CuClientOptions,
+    ) -> Result<String> {
+        let (client, data) =
+            ResilientCuClient::connect_with_data(ip, port, identity, self.policy.clone(), options)
+                .await?;
+        let name = data.name;
+        let mac = client.mac().to_string();
+        self.hubs.lock().unwrap().insert(
+            mac.to_string(),
+            Hub {
+                name,
+                client: Arc::new(client),
+            },
+        );
+        Ok(mac)
+    }
+
+    pub fn remove(&self, key: &str) -> bool {
+        let mut hubs = self.hubs.lock().unwrap();
+        match Self::find_mac(&hubs, key) {
+            Some(mac) => hubs.remove(&mac).is_some(),
+            None => false,
+        }
+    }
+
+    pub fn hubs(&self) -> Vec<HubInfo> {
+        self.hubs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(mac, hub)| HubInfo {
+                mac: mac.to_string(),
+                name: hub.name.to_string(),
+                ip: hub.client.ip(),
+            })
+            .collect()
+    }
+
+    // Keys are matched against the MAC first, then against the central unit name
+    fn find_mac(hubs: &HashMap<String, Hub>, key: &str) -> Option<String> {
+        hubs.keys()
+            .find(|mac| mac.eq_ignore_ascii_case(key))
+            .or_else(|| {
+                hubs.iter()
+                    .find(|(_, hub)| hub.name == key)
+                    .map(|(mac, _)| mac)
+            })
+            .cloned()
+    }
+
+    pub fn hub(&self, key: &str) -> Option<Arc<ResilientCuClient>> {
+        let hubs = self.hubs.lock().unwrap();
+        let mac = Self::find_mac(&hubs, key)?;
+        hubs.get(&mac).map(|hub| Arc::clone(&hub.client))
+    }
+
+    pub async fn unit_operation(&self, hub: &str, op: &UnitItemOperation) -> Result<CuStatus> {
+        match self.hub(hub) {
+            Some(client) => client.unit_operation(op).await,
+            None => Err(unknown_hub(hub)),
+        }
+    }
+
+    pub async fn unit_operation_for(&self, unit: &HubUnit, new_state: i32) -> Result<CuStatus> {
+        self.unit_operation(&unit.hub_mac, &unit.operation(new_state))
+            .await
+    }
+
+    // Queries all central units concurrently, a central unit that doesn't answer within
+    // the timeout is listed with a Timeout error
+    pub async fn get_all(&self) -> Inventory {
+        let hubs: Vec<(String, Arc<ResilientCuClient>)> = self
+            .hubs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(mac, hub)| (mac.to_string(), Arc::clone(&hub.client)))
+            .collect();
+        let timeout = self.timeout;
+        let results = future::join_all(hubs.into_iter().map(|(mac, client)| async move {
+            let result = runtime::timeout(timeout, client.get_all())
+                .await
+                .unwrap_or(Err(CombinedError::Timeout(timeout)));
+            (mac, result)
+        }))
+        .await;
+        let mut inventory = Inventory {
+            units: Vec::new(),
+            errors: Vec::new(),
+        };
+        for (mac, result) in results {
+            let data = match result {
+                Ok(data) => data,
+                Err(e) => {
+                    inventory.errors.push((mac, e));
+                    continue;
+                }
+            };
+            for zone in data.place.iter().flat_map(|place| place.zones.iter()) {
+                for item in &zone.items {
+                    inventory.units.push(HubUnit {
+                        hub_mac: mac.to_string(),
+                        hub_name: data.name.to_string(),
+                        zone: zone.name.to_string(),
+                        item: item.clone(),
+                    });
+                }
+            }
+        }
+        inventory
+    }
+}
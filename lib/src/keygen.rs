@@ -1,51 +1,177 @@
-use openssl::asn1::Asn1Time;
-use openssl::hash::MessageDigest;
-use openssl::nid::Nid;
-use openssl::pkey::{PKey, Private};
-use openssl::rsa::Rsa;
-use openssl::x509::{X509Name, X509};
-use std::time::SystemTime;
-
-pub fn generate_keypair(email: &str, device_name: &str) -> (PKey<Private>, X509) {
-    let rsa = Rsa::generate(2048).unwrap();
-    let pkey = PKey::from_rsa(rsa).unwrap();
-
-    let mut name = X509Name::builder().unwrap();
-    name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "SwitchBee")
-        .unwrap();
-    name.append_entry_by_nid(Nid::ORGANIZATIONALUNITNAME, device_name)
-        .unwrap();
-    name.append_entry_by_nid(Nid::LOCALITYNAME, email).unwrap();
-
-    let name = name.build();
-
-    let mut builder = X509::builder().unwrap();
-    builder.set_version(0).unwrap();
-    builder.set_subject_name(&name).unwrap();
-    builder.set_issuer_name(&name).unwrap();
-    builder.set_pubkey(&pkey).unwrap();
-    builder
-        .set_not_after(Asn1Time::days_from_now(365 * 10).as_ref().unwrap())
-        .unwrap();
-    builder
-        .set_not_before(
-            Asn1Time::from_unix(
-                (SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs()
-                    - 60 * 60 * 24)
-                    .try_into()
-                    .unwrap(),
-            )
-            .as_ref()
-            .unwrap(),
-        )
-        .unwrap(); // from yesterday
-
-    builder.sign(&pkey, MessageDigest::sha256()).unwrap();
-
-    let certificate: X509 = builder.build();
-
-    (pkey, certificate)
+// Key generation and PKCS#12 in pure Rust, shared by both TLS backends so the rustls
+// build doesn't need OpenSSL
+use p12_keystore::{
+    Certificate, EncryptionAlgorithm, KeyStore, KeyStoreEntry, MacAlgorithm, PrivateKeyChain,
+};
+use rcgen::{CertificateParams, DistinguishedName, DnType, PKCS_RSA_SHA256};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::EncodePrivateKey;
+use rsa::rand_core::OsRng;
+use rsa::RsaPrivateKey;
+use sha1::{Digest, Sha1};
+use std::error::Error;
+use std::fmt::{self, Display};
+use time::{Duration, OffsetDateTime};
+
+use crate::api::Result;
+
+#[derive(Debug)]
+pub enum KeyError {
+    Rsa(rsa::Error),
+    Pkcs1(rsa::pkcs1::Error),
+    Pkcs8(rsa::pkcs8::Error),
+    Certificate(rcgen::Error),
+    Pkcs12(p12_keystore::error::Error),
+    // What the archive or PEM input lacks, such as the private key
+    Missing(&'static str),
+}
+
+impl Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Rsa(e) => f.write_fmt(format_args!("RSA key error: {}", e)),
+            KeyError::Pkcs1(e) => f.write_fmt(format_args!("Invalid PKCS#1 key: {}", e)),
+            KeyError::Pkcs8(e) => f.write_fmt(format_args!("Invalid PKCS#8 key: {}", e)),
+            KeyError::Certificate(e) => f.write_fmt(format_args!("Certificate error: {}", e)),
+            KeyError::Pkcs12(e) => f.write_fmt(format_args!("Invalid PKCS#12 archive: {}", e)),
+            KeyError::Missing(what) => f.write_fmt(format_args!("No {} found", what)),
+        }
+    }
+}
+
+impl Error for KeyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KeyError::Rsa(e) => Some(e),
+            KeyError::Pkcs1(e) => Some(e),
+            KeyError::Pkcs8(e) => Some(e),
+            KeyError::Certificate(e) => Some(e),
+            KeyError::Pkcs12(e) => Some(e),
+            KeyError::Missing(_) => None,
+        }
+    }
+}
+
+// Returns the private key as PKCS#8 and the self signed certificate, both DER encoded
+pub fn generate_keypair(email: &str, device_name: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+    let key = RsaPrivateKey::new(&mut OsRng, 2048).map_err(KeyError::Rsa)?;
+    let key = key
+        .to_pkcs8_der()
+        .map_err(KeyError::Pkcs8)?
+        .as_bytes()
+        .to_vec();
+
+    let mut name = DistinguishedName::new();
+    name.push(DnType::OrganizationName, "SwitchBee");
+    name.push(DnType::OrganizationalUnitName, device_name);
+    name.push(DnType::LocalityName, email);
+
+    let mut params = CertificateParams::default();
+    params.alg = &PKCS_RSA_SHA256;
+    params.key_pair = Some(rcgen::KeyPair::from_der(&key).map_err(KeyError::Certificate)?);
+    params.distinguished_name = name;
+    let now = OffsetDateTime::now_utc();
+    // from yesterday
+    params.not_before = now - Duration::days(1);
+    params.not_after = now + Duration::days(365 * 10);
+
+    let cert = rcgen::Certificate::from_params(params)
+        .and_then(|cert| cert.serialize_der())
+        .map_err(KeyError::Certificate)?;
+    Ok((key, cert))
+}
+
+// Packs a key pair as PKCS#12 encrypted with the given password, the format
+// expected by Identity::from_pkcs12
+pub fn export_pkcs12(key: &[u8], cert: &[u8], password: &str) -> Result<Vec<u8>> {
+    export_pkcs12_chain(key, &[cert.to_vec()], password)
+}
+
+// The chain starts with the certificate of the key
+pub(crate) fn export_pkcs12_chain(
+    key: &[u8],
+    chain: &[Vec<u8>],
+    password: &str,
+) -> Result<Vec<u8>> {
+    let certs = chain
+        .iter()
+        .map(|cert| Certificate::from_der(cert))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(KeyError::Pkcs12)?;
+    let leaf = chain.first().ok_or(KeyError::Missing("certificate"))?;
+    let local_key_id = Sha1::digest(leaf);
+    let mut store = KeyStore::new();
+    store.add_entry(
+        "device cert",
+        KeyStoreEntry::PrivateKeyChain(PrivateKeyChain::new(key, local_key_id, certs)),
+    );
+    // Every native-tls backend imports 3DES archives, not all of them import AES ones
+    Ok(store
+        .writer(password)
+        .encryption_algorithm(EncryptionAlgorithm::PbeWithShaAnd3KeyTripleDesCbc)
+        .mac_algorithm(MacAlgorithm::HmacSha1)
+        .write()
+        .map_err(KeyError::Pkcs12)?)
+}
+
+// Returns the PKCS#8 private key and the chain of its certificate, a wrong password
+// fails the MAC check
+pub fn parse_pkcs12(der: &[u8], password: &str) -> Result<(Vec<u8>, Vec<Vec<u8>>)> {
+    let store = KeyStore::from_pkcs12(der, password).map_err(KeyError::Pkcs12)?;
+    let chain = store
+        .entries()
+        .find_map(|(_, entry)| match entry {
+            KeyStoreEntry::PrivateKeyChain(chain) => Some(chain),
+            _ => None,
+        })
+        .ok_or(KeyError::Missing("private key"))?;
+    if chain.chain().is_empty() {
+        return Err(KeyError::Missing("certificate").into());
+    }
+    let certs = chain.chain().iter().map(|cert| cert.as_der().to_vec());
+    Ok((chain.key().to_vec(), certs.collect()))
+}
+
+// PEM keys come as PKCS#8, or as PKCS#1 from older OpenSSL versions
+pub(crate) fn pkcs8_from_pem(label: &str, der: &[u8]) -> Result<Vec<u8>> {
+    match label {
+        "PRIVATE KEY" => Ok(der.to_vec()),
+        "RSA PRIVATE KEY" => {
+            let key = RsaPrivateKey::from_pkcs1_der(der).map_err(KeyError::Pkcs1)?;
+            Ok(key
+                .to_pkcs8_der()
+                .map_err(KeyError::Pkcs8)?
+                .as_bytes()
+                .to_vec())
+        }
+        _ => Err(KeyError::Missing("PKCS#8 or PKCS#1 RSA private key").into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::pkcs1::EncodeRsaPrivateKey;
+    use rsa::pkcs8::DecodePrivateKey;
+
+    #[test]
+    fn pkcs12_round_trip() {
+        let (key, cert) = generate_keypair("test@localhost", "Test device").unwrap();
+        let pkcs12 = export_pkcs12(&key, &cert, "secret").unwrap();
+        let (parsed_key, chain) = parse_pkcs12(&pkcs12, "secret").unwrap();
+        assert_eq!(parsed_key, key);
+        assert_eq!(chain, vec![cert]);
+    }
+
+    #[test]
+    fn pkcs1_pem_key_is_converted_to_pkcs8() {
+        let (key, _) = generate_keypair("test@localhost", "Test device").unwrap();
+        let pkcs1 = RsaPrivateKey::from_pkcs8_der(&key)
+            .unwrap()
+            .to_pkcs1_der()
+            .unwrap();
+        let converted = pkcs8_from_pem("RSA PRIVATE KEY", pkcs1.as_bytes()).unwrap();
+        assert_eq!(converted, key);
+        assert!(pkcs8_from_pem("EC PRIVATE KEY", &key).is_err());
+    }
 }
@@ -1,4 +1,12 @@
 pub mod api;
 pub mod bks;
+pub mod blocking;
+pub mod codec;
+pub mod hub;
 pub mod keygen;
+pub mod mock;
 pub mod protocol;
+pub mod reconnect;
+pub mod runtime;
+pub mod tls;
+pub mod trace;
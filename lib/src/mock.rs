@@ -0,0 +1,373 @@
+use async_channel::{self as channel, Receiver, Sender};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::lock::Mutex;
+use futures::{AsyncReadExt, AsyncWriteExt};
+use std::future::Future;
+use std::str;
+use std::sync::Arc;
+
+use crate::api::*;
+use crate::codec::{FrameReader, FrameWriter, MessageType, MessageWrapper};
+use crate::keygen::{export_pkcs12, generate_keypair};
+use crate::runtime::{self, TcpListener, UdpSocket};
+use crate::tls::{ServerTlsStream, TlsAcceptor};
+
+const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+const DEFAULT_CU_DATA: &str = r#"{
+    "CUVersion": "1.4.6(1)",
+    "ip": "127.0.0.1",
+    "mac": "00:00:5e:00:53:01",
+    "name": "Mock central unit",
+    "port": 23789,
+    "timeStr": "00:00",
+    "timeZone": 0,
+    "timeZoneName": "UTC",
+    "place": {
+        "zones": [
+            {
+                "id": 1,
+                "name": "Living room",
+                "items": [
+                    {"name": "Ceiling light", "unitId": 1, "value": 0, "type": 1},
+                    {"name": "Lamp", "unitId": 2, "value": 100, "type": 1},
+                    {"name": "Dimmer", "unitId": 4, "value": 40, "type": 2}
+                ]
+            },
+            {
+                "id": 2,
+                "name": "Kitchen",
+                "items": [
+                    {"name": "Spot lights", "unitId": 3, "value": 0, "type": 1},
+                    {"name": "Blinds", "unitId": 5, "value": 100, "type": 3},
+                    {"name": "Water heater", "unitId": 7, "value": 0, "type": 4},
+                    {
+                        "name": "Air conditioner",
+                        "unitId": 6,
+                        "value": 0,
+                        "type": 5,
+                        "thermostat": {
+                            "mode": "COOL",
+                            "fan": "AUTO",
+                            "targetTemperature": 24,
+                            "temperature": 27.5
+                        }
+                    }
+                ]
+            }
+        ]
+    }
+}"#;
+
+pub fn default_cu_data() -> CuData {
+    serde_json::from_str(DEFAULT_CU_DATA).unwrap()
+}
+
+struct MockState {
+    data: CuData,
+    // Outgoing message queues of the connected clients, used to push notifications
+    clients: Vec<Sender<MessageWrapper>>,
+    notification_id: u32,
+    // Requests of all clients in the order they were read
+    requests: Vec<MessageWrapper>,
+    // Responses held back while paused, with the queue of the client they are for
+    held: Option<Vec<(Sender<MessageWrapper>, MessageWrapper)>>,
+    // Closed by stop, the serve functions running at that time return
+    stop: Sender<()>,
+    stopped: Receiver<()>,
+}
+
+// Central unit replacement for tests, serves discovery, registration and the TLS
+// protocol from an in-memory CuData model
+#[derive(Clone)]
+pub struct MockCentralUnit {
+    state: Arc<Mutex<MockState>>,
+    acceptor: Arc<TlsAcceptor>,
+}
+
+fn status_json(status: OperationStatus) -> String {
+    serde_json::to_string(&CuStatus { status }).unwrap()
+}
+
+impl MockCentralUnit {
+    pub async fn new(data: CuData) -> Result<Self> {
+        let (key, cert) = generate_keypair("mock@localhost", "Mock central unit")?;
+        let pkcs12 = export_pkcs12(&key, &cert, "1234")?;
+        let acceptor = TlsAcceptor::from_pkcs12(&pkcs12, "1234").await?;
+        let (stop, stopped) = channel::bounded(1);
+        Ok(MockCentralUnit {
+            state: Arc::new(Mutex::new(MockState {
+                data,
+                clients: Vec::new(),
+                notification_id: 1,
+                requests: Vec::new(),
+                held: None,
+                stop,
+                stopped,
+            })),
+            acceptor: Arc::new(acceptor),
+        })
+    }
+
+    pub async fn from_json(json: &str) -> Result<Self> {
+        Self::new(serde_json::from_str(json)?).await
+    }
+
+    // Snapshot of the current model, including values changed by UNOP
+    pub async fn data(&self) -> CuData {
+        self.state.lock().await.data.clone()
+    }
+
+    // Requests read so far, including their message id and priority
+    pub async fn requests(&self) -> Vec<MessageWrapper> {
+        self.state.lock().await.requests.clone()
+    }
+
+    // Keeps handling requests but holds their responses back until resume, like a
+    // central unit that stopped answering
+    pub async fn pause(&self) {
+        let mut state = self.state.lock().await;
+        if state.held.is_none() {
+            state.held = Some(Vec::new());
+        }
+    }
+
+    // Sends the held responses newest first, the central unit doesn't guarantee its
+    // responses come in request order and clients must cope with that
+    pub async fn resume(&self) {
+        let held = self.state.lock().await.held.take();
+        for (client, response) in held.into_iter().flatten().rev() {
+            let _ = client.send(response).await;
+        }
+    }
+
+    // Disconnects every client and makes the running serve functions return, like a
+    // central unit being switched off. Serving again restarts it with the same model
+    pub async fn stop(&self) {
+        let mut state = self.state.lock().await;
+        state.stop.close();
+        let (stop, stopped) = channel::bounded(1);
+        state.stop = stop;
+        state.stopped = stopped;
+        state.clients.clear();
+        state.held = None;
+    }
+
+    async fn until_stopped<F>(&self, serve: F) -> Result<()>
+    where
+        F: Future<Output = Result<()>>,
+    {
+        let stopped = self.state.lock().await.stopped.clone();
+        runtime::race(serve, async move {
+            let _ = stopped.recv().await;
+            Ok(())
+        })
+        .await
+    }
+
+    // Serves discovery, registration and the CU protocol until one of them fails
+    pub async fn run(&self, ip: &str) -> Result<()> {
+        let discovery = UdpSocket::bind((ip, DISCOVERY_PORT)).await?;
+        let registration = TcpListener::bind((ip, REGISTRATION_PORT)).await?;
+        let protocol = TcpListener::bind((ip, CU_PORT as u16)).await?;
+        runtime::race(
+            self.serve_discovery(discovery),
+            runtime::race(
+                self.serve_registration(registration),
+                self.serve_protocol(protocol),
+            ),
+        )
+        .await
+    }
+
+    pub async fn serve_discovery(&self, socket: UdpSocket) -> Result<()> {
+        self.until_stopped(async {
+            let mut buf = [0; 1024];
+            loop {
+                let (size, peer) = socket.recv_from(&mut buf).await?;
+                if &buf[..size] != b"FIND" {
+                    continue;
+                }
+                let mut data = self.data().await;
+                data.place = None;
+                socket
+                    .send_to(serde_json::to_string(&data)?.as_bytes(), peer)
+                    .await?;
+            }
+        })
+        .await
+    }
+
+    pub async fn serve_registration(&self, listener: TcpListener) -> Result<()> {
+        self.until_stopped(async {
+            loop {
+                let stream = runtime::accept(&listener).await?;
+                let acceptor = Arc::clone(&self.acceptor);
+                runtime::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        let _ = Self::handle_registration(stream).await;
+                    }
+                });
+            }
+        })
+        .await
+    }
+
+    // Minimal HTTP handling, enough for a single REGD POST per connection
+    async fn handle_registration(mut stream: ServerTlsStream) -> Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        let header_end = loop {
+            let size = stream.read(&mut buf).await?;
+            if size == 0 {
+                return Ok(());
+            }
+            request.extend_from_slice(&buf[..size]);
+            if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+        let headers = str::from_utf8(&request[..header_end])?.to_ascii_lowercase();
+        let content_length = headers
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .and_then(|value| value.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        while request.len() < header_end + content_length {
+            let size = stream.read(&mut buf).await?;
+            if size == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..size]);
+        }
+        let body = str::from_utf8(&request[header_end..])?;
+        let status = match body
+            .strip_prefix(RegisterDevice::OPCODE)
+            .map(serde_json::from_str::<serde_json::Value>)
+        {
+            Some(Ok(_)) => OperationStatus::OK,
+            _ => OperationStatus::ERROR,
+        };
+        let body = status_json(status);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    pub async fn serve_protocol(&self, listener: TcpListener) -> Result<()> {
+        self.until_stopped(async {
+            loop {
+                let stream = runtime::accept(&listener).await?;
+                let mock = self.clone();
+                runtime::spawn(async move {
+                    if let Ok(stream) = mock.acceptor.accept(stream).await {
+                        let _ = mock.serve_stream(stream).await;
+                    }
+                });
+            }
+        })
+        .await
+    }
+
+    // Serves the CU protocol over an already established stream, the counterpart of
+    // CuClient::from_stream
+    pub async fn serve_stream<S>(&self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send,
+    {
+        let (reader, writer) = futures::AsyncReadExt::split(stream);
+        let mut reader = FrameReader::new(reader, MAX_FRAME_SIZE);
+        let (outgoing, queue) = channel::unbounded::<MessageWrapper>();
+        self.state.lock().await.clients.push(outgoing.clone());
+        let write = async move {
+            let mut writer = FrameWriter::new(writer);
+            while let Ok(message) = queue.recv().await {
+                if writer.write_message(&message).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let read = async {
+            let result = loop {
+                let request = match reader.read_message().await {
+                    Ok(request) => request,
+                    Err(CombinedError::DecodeError(_)) => continue,
+                    Err(e) => break Err(e),
+                };
+                if request.message_type != MessageType::Request {
+                    continue;
+                }
+                self.state.lock().await.requests.push(request.clone());
+                let response = self.handle_request(&request.message).await;
+                let response =
+                    MessageWrapper::new(MessageType::Response, request.message_id, &response)
+                        .with_priority(request.priority);
+                if let Some(held) = self.state.lock().await.held.as_mut() {
+                    held.push((outgoing.clone(), response));
+                    continue;
+                }
+                if outgoing.send(response).await.is_err() {
+                    break Ok(());
+                }
+            };
+            outgoing.close();
+            result
+        };
+        self.until_stopped(async { futures::future::join(read, write).await.0 })
+            .await
+    }
+
+    async fn handle_request(&self, request: &str) -> String {
+        if request == GetAll::OPCODE {
+            return serde_json::to_string(&self.data().await).unwrap();
+        }
+        if let Some(op) = request.strip_prefix(UnitOperation::OPCODE) {
+            return match serde_json::from_str::<UnitItemOperation>(op) {
+                Ok(op) => status_json(self.unit_operation(&op).await),
+                Err(_) => status_json(OperationStatus::ERROR),
+            };
+        }
+        status_json(OperationStatus::ERROR)
+    }
+
+    async fn unit_operation(&self, op: &UnitItemOperation) -> OperationStatus {
+        let mut state = self.state.lock().await;
+        let item = state
+            .data
+            .place
+            .iter_mut()
+            .flat_map(|place| place.zones.iter_mut())
+            .flat_map(|zone| zone.items.iter_mut())
+            .find(|item| item.unit_id == op.unit_id);
+        let item = match item {
+            Some(item) => {
+                item.value = op.new_state;
+                if let (Some(current), Some(settings)) = (&mut item.thermostat, &op.thermostat) {
+                    // The measured temperature isn't a setting
+                    *current = ThermostatState {
+                        temperature: current.temperature,
+                        ..settings.clone()
+                    };
+                }
+                item.clone()
+            }
+            None => return OperationStatus::DeviceNotFound,
+        };
+        let id = state.notification_id;
+        state.notification_id += 1;
+        let notification = MessageWrapper::new(
+            MessageType::Notification,
+            id,
+            &serde_json::to_string(&item).unwrap(),
+        );
+        state
+            .clients
+            .retain(|client| client.try_send(notification.clone()).is_ok());
+        OperationStatus::OK
+    }
+}
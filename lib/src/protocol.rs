@@ -1,130 +1,573 @@
-use async_std::net::TcpStream;
-use async_std::prelude::*;
-use std::str;
+use async_broadcast::{self as broadcast, InactiveReceiver};
+use async_channel::{self as channel, Receiver, Sender};
+use futures::channel::oneshot;
+use futures::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use futures::Stream;
+use sha2::{Digest, Sha256};
+use std::cmp;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex as StdMutex;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span, info, info_span, trace, warn, Instrument, Span};
 
 use crate::api::*;
+use crate::codec::{FrameReader, FrameWriter, MessageType, MessageWrapper};
+use crate::runtime;
+use crate::tls::{self, Identity};
+use crate::trace::{Direction, Tracer};
 
-#[derive(Clone, Copy, Debug)]
-enum MessageType {
-    Request = 1,
-    Response = 2,
-    Notification = 3,
+type CuStream = tls::TlsStream;
+
+// Notifications buffered for the slowest subscriber, the oldest are dropped when it
+// falls further behind
+const NOTIFICATION_BUFFER_SIZE: usize = 256;
+
+// Priorities of outgoing requests, queued requests with a higher priority are written first
+pub const PRIORITY_NORMAL: u8 = 0;
+pub const PRIORITY_HIGH: u8 = 255;
+
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub message_id: u32,
+    pub priority: u8,
+    pub message: String,
+}
+
+impl Notification {
+    pub fn parse<T: serde::de::DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_str(&self.message)?)
+    }
 }
 
-impl TryFrom<u8> for MessageType {
-    type Error = ();
+// Requests waiting for a response keyed by message id, None once the connection is closed
+type PendingRequests = StdMutex<Option<HashMap<u32, oneshot::Sender<Result<MessageWrapper>>>>>;
 
-    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
-        match value {
-            x if x == MessageType::Request as u8 => Ok(MessageType::Request),
-            x if x == MessageType::Response as u8 => Ok(MessageType::Response),
-            x if x == MessageType::Notification as u8 => Ok(MessageType::Notification),
-            _ => Err(()),
+// The central unit has no known ping command, GETA is the only read-only request
+// verified so far. It returns the whole configuration, so it is only sent on idle
+// connections
+pub const DEFAULT_HEARTBEAT_REQUEST: &str = "GETA";
+
+#[derive(Debug, Clone)]
+pub struct CuClientOptions {
+    // Time to wait for the response of a single request, None waits forever
+    pub request_timeout: Option<Duration>,
+    // Idle time after which a heartbeat request is sent, the connection is closed when
+    // one times out. Any message received from the central unit counts as activity
+    pub heartbeat_interval: Option<Duration>,
+    pub heartbeat_request: String,
+    // Frames claiming a larger payload are rejected before allocating a buffer
+    pub max_frame_size: usize,
+    // SHA-256 fingerprint of the central unit certificate recorded on first use, the
+    // connection is refused when the central unit presents a different certificate
+    pub pinned_fingerprint: Option<String>,
+    // Records every sent and received message, see trace::Tracer
+    pub tracer: Option<Arc<Tracer>>,
+}
+
+impl Default for CuClientOptions {
+    fn default() -> Self {
+        CuClientOptions {
+            request_timeout: Some(Duration::from_secs(10)),
+            heartbeat_interval: Some(Duration::from_secs(30)),
+            heartbeat_request: DEFAULT_HEARTBEAT_REQUEST.to_string(),
+            max_frame_size: 4 * 1024 * 1024,
+            pinned_fingerprint: None,
+            tracer: None,
         }
     }
 }
 
-#[derive(Debug)]
-struct MessageWrapper {
-    message_type: MessageType,
-    priority: u8,
-    message_id: u32,
-    message: String,
+// Request waiting to be written, ordered by priority and then by message id
+struct QueuedMessage(MessageWrapper);
+
+impl QueuedMessage {
+    fn key(&self) -> (u8, cmp::Reverse<u32>) {
+        (self.0.priority, cmp::Reverse(self.0.message_id))
+    }
 }
 
-impl MessageWrapper {
-    fn new(message_type: MessageType, message_id: u32, message: &str) -> MessageWrapper {
-        MessageWrapper {
-            message_type,
-            priority: 0,
-            message_id,
-            message: message.to_owned(),
-        }
+impl PartialEq for QueuedMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
     }
-    fn serialize(&self) -> Vec<u8> {
-        let mut result: Vec<u8> = Vec::<u8>::with_capacity(6 + self.message.len());
-        result.push(self.message_type as u8);
-        result.push(self.priority);
-        result.extend_from_slice(&self.message_id.to_le_bytes());
-        result.extend(self.message.as_bytes());
-        result
+}
+
+impl Eq for QueuedMessage {}
+
+impl PartialOrd for QueuedMessage {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
     }
+}
 
-    fn deserialize(data: &[u8]) -> Result<MessageWrapper> {
-        Ok(MessageWrapper {
-            message_type: data[0].try_into().unwrap(),
-            priority: data[1],
-            message_id: u32::from_le_bytes(data[2..6].try_into().unwrap()),
-            message: str::from_utf8(&data[6..])?.to_string(),
-        })
+impl Ord for QueuedMessage {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.key().cmp(&other.key())
     }
 }
 
+// State shared between the client, the reader, writer and heartbeat tasks
+struct Connection {
+    outgoing: StdMutex<BinaryHeap<QueuedMessage>>,
+    // Wakes the writer task when a request is queued
+    wake: Sender<()>,
+    pending: PendingRequests,
+    message_id: AtomicU32,
+    // When the last message was read, the heartbeat is skipped while it is recent
+    received: StdMutex<Instant>,
+    // Closing the channel signals the reader and writer tasks to stop
+    shutdown: Sender<()>,
+    // Parent of the request spans, carries the central unit address
+    span: Span,
+    // ip:port of the central unit, added to the context of request errors
+    address: Option<String>,
+}
+
 pub struct CuClient {
-    stream: async_native_tls::TlsStream<TcpStream>,
-    message_id: u32,
+    connection: Arc<Connection>,
+    // Kept so the channel stays open without buffering while nobody subscribed
+    notifications: InactiveReceiver<Notification>,
+    options: CuClientOptions,
+    // Fingerprint of the certificate the central unit presented, None over streams
+    // given to from_stream
+    fingerprint: Option<String>,
+}
+
+fn connection_closed(reason: &str) -> CombinedError {
+    CombinedError::IoError(std::io::Error::new(
+        std::io::ErrorKind::ConnectionAborted,
+        format!("connection to central unit closed: {}", reason),
+    ))
+}
+
+// Requests start with a four letter opcode, optionally followed by a JSON argument
+fn opcode(request: &str) -> &str {
+    request.get(..4).unwrap_or(request)
+}
+
+impl Connection {
+    async fn request(&self, id: u32, request: &str, priority: u8) -> Result<MessageWrapper> {
+        let (waiter, response) = oneshot::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(waiters) => waiters.insert(id, waiter),
+            None => return Err(connection_closed("reader stopped")),
+        };
+        let message =
+            MessageWrapper::new(MessageType::Request, id, request).with_priority(priority);
+        self.outgoing.lock().unwrap().push(QueuedMessage(message));
+        let _ = self.wake.try_send(());
+        match response.await {
+            Ok(response) => response,
+            Err(_) => Err(connection_closed("reader stopped")),
+        }
+    }
+
+    async fn request_with_timeout(
+        &self,
+        request: &str,
+        priority: u8,
+        timeout: Option<Duration>,
+    ) -> Result<MessageWrapper> {
+        let id = self.message_id.fetch_add(1, Ordering::Relaxed);
+        let span = debug_span!(
+            parent: &self.span,
+            "request",
+            message_id = id,
+            opcode = opcode(request),
+            priority
+        );
+        async {
+            debug!("sending request");
+            let response = match timeout {
+                None => self.request(id, request, priority).await,
+                Some(timeout) => {
+                    match runtime::timeout(timeout, self.request(id, request, priority)).await {
+                        Some(response) => response,
+                        None => {
+                            self.forget(id);
+                            warn!(?timeout, "request timed out");
+                            Err(CombinedError::Timeout(timeout))
+                        }
+                    }
+                }
+            };
+            if response.is_ok() {
+                debug!("response received");
+            }
+            response
+        }
+        .instrument(span)
+        .await
+        .with_context(|| self.describe(request))
+    }
+
+    fn describe(&self, request: &str) -> String {
+        match &self.address {
+            Some(address) => format!("{} request to central unit {}", opcode(request), address),
+            None => format!("{} request", opcode(request)),
+        }
+    }
+
+    fn forget(&self, id: u32) -> Option<oneshot::Sender<Result<MessageWrapper>>> {
+        match self.pending.lock().unwrap().as_mut() {
+            Some(waiters) => waiters.remove(&id),
+            None => None,
+        }
+    }
+
+    fn is_pending(&self, id: u32) -> bool {
+        match self.pending.lock().unwrap().as_ref() {
+            Some(waiters) => waiters.contains_key(&id),
+            None => false,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().is_none()
+    }
+}
+
+pub fn certificate_fingerprint(der: &[u8]) -> String {
+    hex::encode(Sha256::digest(der))
+}
+
+// Fingerprints are compared as lower case hex, pins copied from other tools may be
+// upper case or separated by colons
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+async fn connect_tls(ip: &str, port: u32, identity: &Identity) -> Result<CuStream> {
+    let stream = runtime::connect(&(ip.to_string() + ":" + &port.to_string())).await?;
+    tls::connect(ip, stream, identity).await
+}
+
+fn peer_fingerprint(stream: &CuStream) -> Result<Option<String>> {
+    Ok(tls::peer_certificate(stream)?.map(|der| certificate_fingerprint(&der)))
+}
+
+// Connects once to read the certificate of the central unit, used to pin it on first
+// use and to deliberately re-pin after the central unit was replaced
+pub async fn fetch_certificate_fingerprint(
+    ip: &str,
+    port: u32,
+    identity: Identity,
+) -> Result<String> {
+    let stream = connect_tls(ip, port, &identity)
+        .await
+        .with_context(|| format!("connecting to central unit {}:{}", ip, port))?;
+    match peer_fingerprint(&stream)? {
+        Some(fingerprint) => Ok(fingerprint),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "central unit presented no certificate",
+        )
+        .into()),
+    }
+}
+
+impl Drop for CuClient {
+    fn drop(&mut self) {
+        self.connection.shutdown.close();
+    }
 }
 
 impl CuClient {
-    pub async fn new(ip: &str, port: u32, identity: async_native_tls::Identity) -> Result<Self> {
-        let stream = TcpStream::connect(ip.to_string() + ":" + &port.to_string()).await?;
-        let stream = async_native_tls::TlsConnector::new()
-            .danger_accept_invalid_certs(true)
-            .use_sni(true)
-            .identity(identity)
-            .connect(ip, stream)
+    pub async fn new(ip: &str, port: u32, identity: Identity) -> Result<Self> {
+        Self::with_options(ip, port, identity, CuClientOptions::default()).await
+    }
+
+    pub async fn with_options(
+        ip: &str,
+        port: u32,
+        identity: Identity,
+        options: CuClientOptions,
+    ) -> Result<Self> {
+        let span = info_span!("cu_client", ip, port);
+        let stream = connect_tls(ip, port, &identity)
+            .instrument(span.clone())
             .await
-            .unwrap();
-        Ok(CuClient {
-            stream,
-            message_id: 1,
-        })
-    }
-
-    fn create_prefixed_message(message: &[u8]) -> Vec<u8> {
-        let mut result = Vec::<u8>::with_capacity(8 + message.len());
-        // Magic code
-        result.push(127);
-        result.push(54);
-        result.push(60);
-        result.push(162);
-
-        result.extend_from_slice(&(message.len() as u32).to_le_bytes());
-        result.extend(message);
-
-        result
-    }
-
-    async fn read_prefixed_message(&mut self) -> Result<Vec<u8>> {
-        let mut _magic = [0; 4];
-        self.stream.read_exact(&mut _magic).await?;
-
-        let mut size = [0; 4];
-        self.stream.read_exact(&mut size).await?;
-        let size = u32::from_le_bytes(size);
-
-        let size: usize = size.try_into().unwrap();
-        let mut buffer = Vec::with_capacity(size);
-        unsafe { buffer.set_len(size) }
-        self.stream.read_exact(&mut buffer).await?;
-        Ok(buffer)
-    }
-
-    pub async fn request(&mut self, request: &str) -> Result<String> {
-        let id = self.message_id;
-        let message = MessageWrapper::new(MessageType::Request, id, request);
-        let message = Self::create_prefixed_message(&message.serialize());
-        self.stream.write_all(&message).await?;
-        self.message_id += 1;
-        let buf = self.read_prefixed_message().await?;
-        let response = MessageWrapper::deserialize(&buf).unwrap();
-        if response.message_id != id {
-            return Err(CombinedError::ApiError(ApiError {
-                status: OperationStatus::OK,
-                message: response.message,
-                is_wrong_message_id: true,
-            }));
+            .with_context(|| format!("connecting to central unit {}:{}", ip, port))?;
+        let fingerprint = peer_fingerprint(&stream)?;
+        if let Some(expected) = &options.pinned_fingerprint {
+            if fingerprint.as_deref() != Some(normalize_fingerprint(expected).as_str()) {
+                span.in_scope(
+                    || warn!(expected, actual = ?fingerprint, "certificate pin mismatch"),
+                );
+                return Err(CombinedError::CertificatePinError(CertificatePinError {
+                    expected: expected.to_string(),
+                    actual: fingerprint,
+                }));
+            }
+        }
+        let mut client = Self::start(stream, options, span, Some(format!("{}:{}", ip, port)));
+        client.fingerprint = fingerprint;
+        Ok(client)
+    }
+
+    // Runs the protocol over an already established stream, such as a
+    // trace::ReplayTransport
+    pub fn from_stream<S>(stream: S, options: CuClientOptions) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        Self::start(stream, options, info_span!("cu_client"), None)
+    }
+
+    fn start<S>(stream: S, options: CuClientOptions, span: Span, address: Option<String>) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        span.in_scope(|| info!("connected"));
+        let (reader, writer) = futures::AsyncReadExt::split(stream);
+        let (shutdown, shutdown_rx) = channel::bounded(1);
+        let (wake, wake_rx) = channel::bounded(1);
+        let connection = Arc::new(Connection {
+            outgoing: StdMutex::new(BinaryHeap::new()),
+            wake,
+            pending: StdMutex::new(Some(HashMap::new())),
+            message_id: AtomicU32::new(1),
+            received: StdMutex::new(Instant::now()),
+            shutdown,
+            span: span.clone(),
+            address,
+        });
+        let (mut notifications_tx, notifications) = broadcast::broadcast(NOTIFICATION_BUFFER_SIZE);
+        notifications_tx.set_overflow(true);
+        let notifications = notifications.deactivate();
+        runtime::spawn(
+            Self::read_loop(
+                FrameReader::new(reader, options.max_frame_size),
+                Arc::clone(&connection),
+                notifications_tx,
+                shutdown_rx.clone(),
+                options.tracer.clone(),
+            )
+            .instrument(span.clone()),
+        );
+        runtime::spawn(
+            Self::write_loop(
+                FrameWriter::new(writer),
+                Arc::clone(&connection),
+                wake_rx,
+                shutdown_rx,
+                options.tracer.clone(),
+            )
+            .instrument(span.clone()),
+        );
+        if let Some(interval) = options.heartbeat_interval {
+            runtime::spawn(
+                Self::heartbeat_loop(
+                    Arc::downgrade(&connection),
+                    options.heartbeat_request.clone(),
+                    interval,
+                    options.request_timeout.unwrap_or(interval),
+                )
+                .instrument(span),
+            );
+        }
+        CuClient {
+            connection,
+            notifications,
+            options,
+            fingerprint: None,
+        }
+    }
+
+    // Reads frames until the connection fails or the client is dropped, routing
+    // responses to their waiting request and notifications to the notification stream
+    async fn read_loop<S: AsyncRead>(
+        mut reader: FrameReader<ReadHalf<S>>,
+        connection: Arc<Connection>,
+        notifications: broadcast::Sender<Notification>,
+        shutdown: Receiver<()>,
+        tracer: Option<Arc<Tracer>>,
+    ) {
+        let reason = loop {
+            let message = runtime::race(async { Some(reader.read_message().await) }, async {
+                let _ = shutdown.recv().await;
+                None
+            })
+            .await;
+            let message = match message {
+                None => break "connection shut down".to_string(),
+                Some(Ok(message)) => message,
+                // The frame was fully read so the stream is still in sync, skip it
+                Some(Err(CombinedError::DecodeError(e))) => {
+                    warn!(error = %e, "skipping undecodable frame");
+                    continue;
+                }
+                Some(Err(e)) => break e.to_string(),
+            };
+            *connection.received.lock().unwrap() = Instant::now();
+            if let Some(tracer) = &tracer {
+                tracer.record(Direction::Received, &message);
+            }
+            match message.message_type {
+                MessageType::Notification => {
+                    trace!(message_id = message.message_id, "notification received");
+                    let notification = Notification {
+                        message_id: message.message_id,
+                        priority: message.priority,
+                        message: message.message,
+                    };
+                    match notifications.try_broadcast(notification) {
+                        Ok(None) => (),
+                        Ok(Some(dropped)) => warn!(
+                            message_id = dropped.message_id,
+                            "notification buffer full, dropping the oldest notification"
+                        ),
+                        // Nobody subscribed, the notification isn't buffered
+                        Err(_) => trace!(
+                            message_id = message.message_id,
+                            "no notification subscriber"
+                        ),
+                    }
+                }
+                MessageType::Response => {
+                    let waiter = match connection.pending.lock().unwrap().as_mut() {
+                        Some(waiters) => waiters.remove(&message.message_id),
+                        None => None,
+                    };
+                    // Responses nobody is waiting for are dropped
+                    match waiter {
+                        Some(waiter) => {
+                            let _ = waiter.send(Ok(message));
+                        }
+                        None => debug!(
+                            message_id = message.message_id,
+                            "dropping response nobody waits for"
+                        ),
+                    }
+                }
+                MessageType::Request => (),
+            }
+        };
+        info!(%reason, "connection closed");
+        let waiters = connection.pending.lock().unwrap().take();
+        for (_, waiter) in waiters.into_iter().flatten() {
+            let _ = waiter.send(Err(connection_closed(&reason)));
+        }
+    }
+
+    // Writes queued requests, highest priority first, until the connection shuts down
+    async fn write_loop<S: AsyncWrite>(
+        mut writer: FrameWriter<WriteHalf<S>>,
+        connection: Arc<Connection>,
+        wake: Receiver<()>,
+        shutdown: Receiver<()>,
+        tracer: Option<Arc<Tracer>>,
+    ) {
+        loop {
+            let next = connection.outgoing.lock().unwrap().pop();
+            let message = match next {
+                Some(QueuedMessage(message)) => message,
+                None => {
+                    if runtime::race(wake.recv(), shutdown.recv()).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            // Requests that timed out while queued are not sent
+            if !connection.is_pending(message.message_id) {
+                continue;
+            }
+            if let Some(tracer) = &tracer {
+                tracer.record(Direction::Sent, &message);
+            }
+            if let Err(e) = writer.write_message(&message).await {
+                warn!(message_id = message.message_id, error = ?e, "write failed");
+                if let Some(waiter) = connection.forget(message.message_id) {
+                    let _ = waiter.send(Err(e));
+                }
+                connection.shutdown.close();
+                return;
+            }
         }
+    }
+
+    // Checks an idle central unit still answers, closing a dead connection so pending
+    // and future requests fail instead of hanging
+    async fn heartbeat_loop(
+        connection: Weak<Connection>,
+        request: String,
+        interval: Duration,
+        timeout: Duration,
+    ) {
+        let mut wait = interval;
+        loop {
+            runtime::sleep(wait).await;
+            let connection = match connection.upgrade() {
+                Some(connection) => connection,
+                None => return,
+            };
+            if connection.is_closed() {
+                return;
+            }
+            let idle = connection.received.lock().unwrap().elapsed();
+            if idle < interval {
+                wait = interval - idle;
+                continue;
+            }
+            wait = interval;
+            let result = connection
+                .request_with_timeout(&request, PRIORITY_NORMAL, Some(timeout))
+                .await;
+            if let Err(CombinedError::Timeout(_)) = result.as_ref().map_err(|e| e.root()) {
+                warn!("heartbeat timed out, closing the connection");
+                connection.shutdown.close();
+                return;
+            }
+        }
+    }
+
+    // Pinned on first use by callers that had no pin for this central unit yet
+    pub fn certificate_fingerprint(&self) -> Option<&str> {
+        self.fingerprint.as_deref()
+    }
+
+    // Stream of the notifications pushed by the central unit from now on, every stream
+    // returned by this function receives every notification
+    pub fn notifications(&self) -> impl Stream<Item = Notification> {
+        self.notifications.activate_cloned()
+    }
+
+    // Requests may be issued concurrently, responses are matched by message id
+    pub async fn request(&self, request: &str) -> Result<String> {
+        let response = self.request_with_priority(request, PRIORITY_NORMAL).await?;
         Ok(response.message)
     }
+
+    pub async fn execute<C: Command>(&self, request: &C::Request) -> Result<C::Response> {
+        self.execute_with_priority::<C>(request, PRIORITY_NORMAL)
+            .await
+    }
+
+    pub async fn execute_with_priority<C: Command>(
+        &self,
+        request: &C::Request,
+        priority: u8,
+    ) -> Result<C::Response> {
+        let request = C::encode(request)?;
+        let response = self.request_with_priority(&request, priority).await?;
+        C::decode(&response.message).with_context(|| self.connection.describe(&request))
+    }
+
+    // Returns the whole response message, including the priority set by the central unit
+    pub async fn request_with_priority(
+        &self,
+        request: &str,
+        priority: u8,
+    ) -> Result<MessageWrapper> {
+        self.connection
+            .request_with_timeout(request, priority, self.options.request_timeout)
+            .await
+    }
 }
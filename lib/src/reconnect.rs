@@ -0,0 +1,389 @@
+use async_broadcast::{self as broadcast, InactiveReceiver};
+use futures::lock::Mutex;
+use futures::{Stream, StreamExt};
+use std::sync::Mutex as StdMutex;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::api::*;
+use crate::protocol::{CuClient, CuClientOptions, Notification};
+use crate::runtime;
+use crate::tls::Identity;
+
+// Notifications and connection events buffered for the slowest subscriber
+const BUFFER_SIZE: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // Connection attempts before the request fails, None retries forever
+    pub max_attempts: Option<u32>,
+    // How many times an idempotent request is retried after a reconnect
+    pub request_retries: u32,
+    // ip:port FIND probes are sent to when the central unit no longer answers at its IP
+    pub discovery_address: String,
+    pub discovery_timeout: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: Some(5),
+            request_retries: 2,
+            discovery_address: format!("{}:{}", DISCOVERY_ADDRESS, DISCOVERY_PORT),
+            discovery_timeout: DISCOVERY_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ConnectionEvent {
+    // The connection failed, it is reconnected in the background
+    Disconnected,
+    // Connected again, possibly at another IP. Notifications sent while disconnected
+    // are lost, data is the state of the central unit once reconnected
+    Reconnected { ip: String, data: Box<CuData> },
+}
+
+struct Connection {
+    ip: String,
+    client: Option<Arc<CuClient>>,
+}
+
+// State shared with the tasks following the current connection
+struct Shared {
+    identity: Identity,
+    port: u32,
+    mac: String,
+    policy: ReconnectPolicy,
+    options: CuClientOptions,
+    connection: StdMutex<Connection>,
+    // Held for a single connection attempt, never across the backoff, so callers
+    // waiting for it give up after their own attempts instead of waiting forever
+    connecting: Mutex<()>,
+    notifications: broadcast::Sender<Notification>,
+    events: broadcast::Sender<ConnectionEvent>,
+}
+
+// CuClient wrapper that reconnects when the connection to the central unit drops,
+// re-discovering the central unit by its MAC address if its IP changed
+pub struct ResilientCuClient {
+    shared: Arc<Shared>,
+    // Kept so the channels stay open without buffering while nobody subscribed
+    notifications: InactiveReceiver<Notification>,
+    events: InactiveReceiver<ConnectionEvent>,
+}
+
+fn is_connection_error(err: &CombinedError) -> bool {
+    match err.root() {
+        CombinedError::IoError(_) | CombinedError::Timeout(_) => true,
+        #[cfg(feature = "native-tls")]
+        CombinedError::AsyncTlsError(_) => true,
+        #[cfg(feature = "rustls")]
+        CombinedError::RustlsError(_) => true,
+        _ => false,
+    }
+}
+
+fn wrong_central_unit(ip: &str, expected: &str, actual: &str) -> CombinedError {
+    CombinedError::IoError(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "central unit at {} has MAC {}, expected {}",
+            ip, actual, expected
+        ),
+    ))
+}
+
+fn channel<T: Clone>() -> (broadcast::Sender<T>, InactiveReceiver<T>) {
+    let (mut sender, receiver) = broadcast::broadcast(BUFFER_SIZE);
+    sender.set_overflow(true);
+    (sender, receiver.deactivate())
+}
+
+impl Shared {
+    fn ip(&self) -> String {
+        self.connection.lock().unwrap().ip.to_string()
+    }
+
+    fn current(&self) -> Option<Arc<CuClient>> {
+        self.connection.lock().unwrap().client.clone()
+    }
+
+    // An attempt to an unreachable address is bounded by the request timeout. A DHCP
+    // change may have given the IP to another central unit, its MAC is checked
+    async fn connect(&self, ip: &str) -> Result<(CuClient, CuData)> {
+        let connect =
+            CuClient::with_options(ip, self.port, self.identity.clone(), self.options.clone());
+        let client = match self.options.request_timeout {
+            Some(timeout) => runtime::timeout(timeout, connect).await.unwrap_or_else(|| {
+                Err(CombinedError::Timeout(timeout)
+                    .context(format!("connecting to central unit {}:{}", ip, self.port)))
+            }),
+            None => connect.await,
+        }?;
+        let data = client.get_all().await?;
+        if data.mac != self.mac {
+            return Err(wrong_central_unit(ip, &self.mac, &data.mac));
+        }
+        Ok((client, data))
+    }
+
+    async fn connect_once(&self) -> Result<(CuClient, CuData)> {
+        let ip = self.ip();
+        match self.connect(&ip).await {
+            Ok(connected) => Ok(connected),
+            Err(e) => {
+                let found = find_central_unit(
+                    &self.policy.discovery_address,
+                    &self.mac,
+                    self.policy.discovery_timeout,
+                )
+                .await?;
+                match found {
+                    Some(cu) if cu.CUIP != ip => {
+                        info!(mac = %self.mac, old_ip = %ip, new_ip = %cu.CUIP, "central unit moved");
+                        self.connection.lock().unwrap().ip = cu.CUIP.to_string();
+                        self.connect(&cu.CUIP).await
+                    }
+                    _ => Err(e),
+                }
+            }
+        }
+    }
+
+    // Connects once under the connecting lock, or returns the client another caller
+    // connected while this one waited for the lock
+    async fn attempt(self: &Arc<Self>) -> Result<Arc<CuClient>> {
+        let _connecting = self.connecting.lock().await;
+        if let Some(client) = self.current() {
+            return Ok(client);
+        }
+        let (client, data) = self.connect_once().await?;
+        let client = Arc::new(client);
+        self.connection.lock().unwrap().client = Some(Arc::clone(&client));
+        self.follow(&client);
+        info!(mac = %self.mac, "reconnected to central unit");
+        let _ = self.events.try_broadcast(ConnectionEvent::Reconnected {
+            ip: self.ip(),
+            data: Box::new(data),
+        });
+        Ok(client)
+    }
+
+    // Connects with a growing backoff between attempts, None when the client was
+    // dropped in the meantime
+    async fn reconnect(shared: &Weak<Self>) -> Option<Result<Arc<CuClient>>> {
+        let mut backoff = Duration::ZERO;
+        let mut attempt = 0;
+        loop {
+            let this = shared.upgrade()?;
+            attempt += 1;
+            let error = match this.attempt().await {
+                Ok(client) => return Some(Ok(client)),
+                Err(e) => e,
+            };
+            warn!(mac = %this.mac, attempt, error = %error, "reconnect failed");
+            if let Some(max_attempts) = this.policy.max_attempts {
+                if attempt >= max_attempts {
+                    return Some(Err(error));
+                }
+            }
+            backoff = match attempt {
+                1 => this.policy.initial_backoff,
+                _ => std::cmp::min(backoff * 2, this.policy.max_backoff),
+            };
+            drop(this);
+            runtime::sleep(backoff).await;
+        }
+    }
+
+    // Forwards the notifications of a connection until it closes, then drops it so it
+    // is reconnected without waiting for the next request
+    fn follow(self: &Arc<Self>, client: &Arc<CuClient>) {
+        let notifications = client.notifications();
+        let sender = self.notifications.clone();
+        let shared = Arc::downgrade(self);
+        let client = Arc::downgrade(client);
+        runtime::spawn(async move {
+            futures::pin_mut!(notifications);
+            while let Some(notification) = notifications.next().await {
+                if let Ok(Some(dropped)) = sender.try_broadcast(notification) {
+                    warn!(
+                        message_id = dropped.message_id,
+                        "notification buffer full, dropping the oldest notification"
+                    );
+                }
+            }
+            if let (Some(shared), Some(client)) = (shared.upgrade(), client.upgrade()) {
+                shared.drop_client(&client);
+            }
+        });
+    }
+
+    fn drop_client(self: &Arc<Self>, failed: &Arc<CuClient>) {
+        {
+            let mut connection = self.connection.lock().unwrap();
+            // Another request may have already replaced the failed client
+            match &connection.client {
+                Some(current) if Arc::ptr_eq(current, failed) => connection.client = None,
+                _ => return,
+            }
+        }
+        info!(mac = %self.mac, "disconnected from central unit");
+        let _ = self.events.try_broadcast(ConnectionEvent::Disconnected);
+        let shared = Arc::downgrade(self);
+        runtime::spawn(async move {
+            let _ = Self::reconnect(&shared).await;
+        });
+    }
+}
+
+impl ResilientCuClient {
+    pub async fn new(
+        ip: &str,
+        port: u32,
+        identity: Identity,
+        policy: ReconnectPolicy,
+    ) -> Result<Self> {
+        Self::with_options(ip, port, identity, policy, CuClientOptions::default()).await
+    }
+
+    pub async fn with_options(
+        ip: &str,
+        port: u32,
+        identity: Identity,
+        policy: ReconnectPolicy,
+        options: CuClientOptions,
+    ) -> Result<Self> {
+        let (client, _) = Self::connect_with_data(ip, port, identity, policy, options).await?;
+        Ok(client)
+    }
+
+    // Like with_options, also returning the state of the central unit read while
+    // connecting
+    pub(crate) async fn connect_with_data(
+        ip: &str,
+        port: u32,
+        identity: Identity,
+        policy: ReconnectPolicy,
+        options: CuClientOptions,
+    ) -> Result<(Self, CuData)> {
+        let (client, data) = Self::connect(ip, port, identity.clone(), options.clone()).await?;
+        let client = Arc::new(client);
+        let (notifications_tx, notifications) = channel();
+        let (events_tx, events) = channel();
+        let shared = Arc::new(Shared {
+            identity,
+            port,
+            mac: data.mac.to_string(),
+            policy,
+            options,
+            connection: StdMutex::new(Connection {
+                ip: ip.to_string(),
+                client: Some(Arc::clone(&client)),
+            }),
+            connecting: Mutex::new(()),
+            notifications: notifications_tx,
+            events: events_tx,
+        });
+        shared.follow(&client);
+        let client = ResilientCuClient {
+            shared,
+            notifications,
+            events,
+        };
+        Ok((client, data))
+    }
+
+    async fn connect(
+        ip: &str,
+        port: u32,
+        identity: Identity,
+        options: CuClientOptions,
+    ) -> Result<(CuClient, CuData)> {
+        let client = CuClient::with_options(ip, port, identity, options).await?;
+        let data = client.get_all().await?;
+        Ok((client, data))
+    }
+
+    pub fn mac(&self) -> &str {
+        &self.shared.mac
+    }
+
+    pub fn ip(&self) -> String {
+        self.shared.ip()
+    }
+
+    // Notifications of the current connection and of the connections after it, see
+    // CuClient::notifications
+    pub fn notifications(&self) -> impl Stream<Item = Notification> {
+        self.notifications.activate_cloned()
+    }
+
+    pub fn events(&self) -> impl Stream<Item = ConnectionEvent> {
+        self.events.activate_cloned()
+    }
+
+    async fn client(&self) -> Result<Arc<CuClient>> {
+        if let Some(client) = self.shared.current() {
+            return Ok(client);
+        }
+        match Shared::reconnect(&Arc::downgrade(&self.shared)).await {
+            Some(result) => result,
+            // self keeps the shared state alive
+            None => unreachable!(),
+        }
+    }
+
+    async fn with_retries<T, F, Fut>(&self, retries: u32, operation: F) -> Result<T>
+    where
+        F: Fn(Arc<CuClient>) -> Fut,
+        Fut: std::future::Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let client = self.client().await?;
+            match operation(Arc::clone(&client)).await {
+                Err(e) if is_connection_error(&e) => {
+                    self.shared.drop_client(&client);
+                    if attempt >= retries {
+                        return Err(e);
+                    }
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Raw requests carry no Command type telling whether they are idempotent, so they
+    // are never retried, use execute for that
+    pub async fn request(&self, request: &str) -> Result<String> {
+        self.with_retries(0, |client| async move { client.request(request).await })
+            .await
+    }
+
+    pub async fn execute<C: Command>(&self, request: &C::Request) -> Result<C::Response> {
+        let retries = match C::IDEMPOTENT {
+            true => self.shared.policy.request_retries,
+            false => 0,
+        };
+        self.with_retries(retries, |client| async move {
+            client.execute::<C>(request).await
+        })
+        .await
+    }
+
+    pub async fn get_all(&self) -> Result<CuData> {
+        self.execute::<GetAll>(&()).await
+    }
+
+    pub async fn unit_operation(&self, op: &UnitItemOperation) -> Result<CuStatus> {
+        self.execute::<UnitOperation>(op).await
+    }
+}
@@ -0,0 +1,42 @@
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+pub use async_std::fs::{read, read_to_string, write};
+pub use async_std::net::{TcpListener, TcpStream, UdpSocket};
+
+pub async fn connect(addr: &str) -> io::Result<TcpStream> {
+    TcpStream::connect(addr).await
+}
+
+// Accepted connections answer with several small writes, Nagle's algorithm would delay
+// each one until the previous is acknowledged
+pub async fn accept(listener: &TcpListener) -> io::Result<TcpStream> {
+    let stream = listener.accept().await?.0;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    async_std::task::spawn(future);
+}
+
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    async_std::task::block_on(future)
+}
+
+// async-std supports blocking from within a task, it never fails
+pub fn try_block_on<F: Future>(future: F) -> io::Result<F::Output> {
+    Ok(block_on(future))
+}
+
+pub async fn sleep(duration: Duration) {
+    async_std::task::sleep(duration).await
+}
+
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    async_std::future::timeout(duration, future).await.ok()
+}
@@ -0,0 +1,25 @@
+// Async runtime used for sockets, timers, files and background tasks, async-std by
+// default and tokio when the "runtime-tokio" feature is enabled
+use futures::future::{self, Either};
+use std::future::Future;
+
+#[cfg(not(any(feature = "runtime-async-std", feature = "runtime-tokio")))]
+compile_error!("either the \"runtime-async-std\" or the \"runtime-tokio\" feature must be enabled");
+
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+mod async_std_rt;
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+pub use async_std_rt::*;
+
+#[cfg(feature = "runtime-tokio")]
+mod tokio_rt;
+#[cfg(feature = "runtime-tokio")]
+pub use tokio_rt::*;
+
+// Resolves to the output of whichever future completes first
+pub async fn race<T>(a: impl Future<Output = T>, b: impl Future<Output = T>) -> T {
+    futures::pin_mut!(a, b);
+    match future::select(a, b).await {
+        Either::Left((output, _)) | Either::Right((output, _)) => output,
+    }
+}
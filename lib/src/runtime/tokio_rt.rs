@@ -0,0 +1,67 @@
+use std::future::Future;
+use std::io;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+pub use tokio::fs::{read, read_to_string, write};
+pub use tokio::net::{TcpListener, UdpSocket};
+
+// The codec and TLS layers are written against the futures io traits
+pub type TcpStream = Compat<tokio::net::TcpStream>;
+
+pub async fn connect(addr: &str) -> io::Result<TcpStream> {
+    Ok(tokio::net::TcpStream::connect(addr).await?.compat())
+}
+
+// Accepted connections answer with several small writes, Nagle's algorithm would delay
+// each one until the previous is acknowledged
+pub async fn accept(listener: &TcpListener) -> io::Result<TcpStream> {
+    let stream = listener.accept().await?.0;
+    stream.set_nodelay(true)?;
+    Ok(stream.compat())
+}
+
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(future);
+}
+
+// Runtime owned by the library for the blocking API, its worker keeps background
+// tasks such as the connection loops running between blocking calls
+fn blocking_runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .expect("failed to start the tokio runtime")
+    })
+}
+
+// Panics when called from within a tokio runtime, like tokio's own block_on
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    blocking_runtime().block_on(future)
+}
+
+// Like block_on but fails instead of panicking within a tokio runtime, where blocking
+// would also stall the worker driving the connection
+pub fn try_block_on<F: Future>(future: F) -> io::Result<F::Output> {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        return Err(io::Error::other(
+            "blocking call from within a tokio runtime, use the async API instead",
+        ));
+    }
+    Ok(block_on(future))
+}
+
+pub async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    tokio::time::timeout(duration, future).await.ok()
+}
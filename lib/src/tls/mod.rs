@@ -0,0 +1,54 @@
+// TLS backend used by the CU protocol, the registration client and the mock central
+// unit, native-tls by default and rustls when the "rustls" feature is enabled
+use x509_parser::pem::Pem;
+
+use crate::api::Result;
+use crate::keygen::{export_pkcs12_chain, pkcs8_from_pem};
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("either the \"native-tls\" or the \"rustls\" feature must be enabled");
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+mod native;
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub use native::*;
+
+#[cfg(feature = "rustls")]
+mod rustls_tls;
+#[cfg(feature = "rustls")]
+pub use rustls_tls::*;
+
+// Password of the transient archive PEM identities are repacked into
+const PEM_REPACK_PASSWORD: &str = "bswitch";
+
+fn invalid_pem(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn pem_blocks(pem: &[u8]) -> Result<Vec<Pem>> {
+    Ok(Pem::iter_from_buffer(pem)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| invalid_pem(format!("invalid PEM: {}", e)))?)
+}
+
+impl Identity {
+    // The certificate PEM may hold a chain, the device certificate comes first. The
+    // pair is repacked as PKCS#12 so both backends share one loading path
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> Result<Self> {
+        let chain = pem_blocks(cert)?
+            .into_iter()
+            .filter(|pem| pem.label == "CERTIFICATE")
+            .map(|pem| pem.contents)
+            .collect::<Vec<_>>();
+        if chain.is_empty() {
+            return Err(invalid_pem("no certificate in PEM".to_string()).into());
+        }
+        let key = pem_blocks(key)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| invalid_pem("no private key in PEM".to_string()))?;
+        let key = pkcs8_from_pem(&key.label, &key.contents)?;
+        let pkcs12 = export_pkcs12_chain(&key, &chain, PEM_REPACK_PASSWORD)?;
+        Self::from_pkcs12(&pkcs12, PEM_REPACK_PASSWORD)
+    }
+}
@@ -0,0 +1,71 @@
+use crate::runtime::TcpStream;
+
+use crate::api::{CombinedError, Result};
+
+pub type TlsStream = async_native_tls::TlsStream<TcpStream>;
+pub type ServerTlsStream = async_native_tls::TlsStream<TcpStream>;
+
+// reqwest takes the identity as raw PKCS#12, so the archive is kept next to the parsed one
+#[derive(Clone)]
+pub struct Identity {
+    identity: async_native_tls::Identity,
+    pkcs12: Vec<u8>,
+    password: String,
+}
+
+impl Identity {
+    pub fn from_pkcs12(der: &[u8], password: &str) -> Result<Self> {
+        Ok(Identity {
+            identity: async_native_tls::Identity::from_pkcs12(der, password)?,
+            pkcs12: der.to_vec(),
+            password: password.to_string(),
+        })
+    }
+}
+
+// The central unit uses a self signed certificate, it is verified by pinning instead
+pub async fn connect(domain: &str, stream: TcpStream, identity: &Identity) -> Result<TlsStream> {
+    Ok(async_native_tls::TlsConnector::new()
+        .danger_accept_invalid_certs(true)
+        .use_sni(true)
+        .identity(identity.identity.clone())
+        .connect(domain, stream)
+        .await?)
+}
+
+pub fn peer_certificate(stream: &TlsStream) -> Result<Option<Vec<u8>>> {
+    match stream.peer_certificate()? {
+        Some(cert) => Ok(Some(cert.to_der()?)),
+        None => Ok(None),
+    }
+}
+
+// Registration is the first use of the central unit certificate, there is nothing to
+// verify it against yet. tls_info exposes it so register_device can pin it
+pub fn https_client_builder(identity: &Identity) -> Result<reqwest::ClientBuilder> {
+    Ok(reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .tls_info(true)
+        .identity(reqwest::Identity::from_pkcs12_der(
+            &identity.pkcs12,
+            &identity.password,
+        )?))
+}
+
+pub struct TlsAcceptor(async_native_tls::TlsAcceptor);
+
+impl TlsAcceptor {
+    pub async fn from_pkcs12(der: &[u8], password: &str) -> Result<Self> {
+        let acceptor = async_native_tls::TlsAcceptor::new(der, password)
+            .await
+            .map_err(|e| match e {
+                async_native_tls::AcceptError::NativeTls(e) => CombinedError::AsyncTlsError(e),
+                async_native_tls::AcceptError::Io(e) => CombinedError::IoError(e),
+            })?;
+        Ok(TlsAcceptor(acceptor))
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> Result<ServerTlsStream> {
+        Ok(self.0.accept(stream).await?)
+    }
+}
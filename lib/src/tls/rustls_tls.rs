@@ -0,0 +1,165 @@
+use futures_rustls::rustls::client::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use futures_rustls::rustls::{
+    self, Certificate, CertificateError, ClientConfig, DigitallySignedStruct, PrivateKey,
+    ServerConfig, ServerName, SignatureScheme,
+};
+use ring::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use std::io;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::api::Result;
+use crate::keygen::parse_pkcs12;
+use crate::runtime::TcpStream;
+
+pub type TlsStream = futures_rustls::client::TlsStream<TcpStream>;
+pub type ServerTlsStream = futures_rustls::server::TlsStream<TcpStream>;
+
+#[derive(Clone)]
+pub struct Identity {
+    cert_chain: Vec<Certificate>,
+    key: PrivateKey,
+}
+
+impl Identity {
+    // rustls has no PKCS#12 support, the archive is unpacked by keygen
+    pub fn from_pkcs12(der: &[u8], password: &str) -> Result<Self> {
+        let (key, chain) = parse_pkcs12(der, password)?;
+        Ok(Identity {
+            cert_chain: chain.into_iter().map(Certificate).collect(),
+            key: PrivateKey(key),
+        })
+    }
+}
+
+// The central unit uses a self signed certificate, it is verified by pinning instead.
+// Handshake signatures are still checked, with ring directly since webpki rejects the
+// X.509 v1 certificates produced by the central unit
+struct AcceptAnyCertificate;
+
+// ring has no P-521 support
+const VERIFY_SCHEMES: [SignatureScheme; 8] = [
+    SignatureScheme::RSA_PKCS1_SHA256,
+    SignatureScheme::RSA_PKCS1_SHA384,
+    SignatureScheme::RSA_PKCS1_SHA512,
+    SignatureScheme::RSA_PSS_SHA256,
+    SignatureScheme::RSA_PSS_SHA384,
+    SignatureScheme::RSA_PSS_SHA512,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+];
+
+fn verify_signature(
+    message: &[u8],
+    cert: &Certificate,
+    dss: &DigitallySignedStruct,
+) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+    // The legacy variants also accept 1024 bit keys, like OpenSSL did before
+    let algorithm: &dyn VerificationAlgorithm = match dss.scheme {
+        SignatureScheme::RSA_PKCS1_SHA256 => {
+            &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY
+        }
+        SignatureScheme::RSA_PKCS1_SHA384 => &signature::RSA_PKCS1_2048_8192_SHA384,
+        SignatureScheme::RSA_PKCS1_SHA512 => {
+            &signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY
+        }
+        SignatureScheme::RSA_PSS_SHA256 => &signature::RSA_PSS_2048_8192_SHA256,
+        SignatureScheme::RSA_PSS_SHA384 => &signature::RSA_PSS_2048_8192_SHA384,
+        SignatureScheme::RSA_PSS_SHA512 => &signature::RSA_PSS_2048_8192_SHA512,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &signature::ECDSA_P256_SHA256_ASN1,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &signature::ECDSA_P384_SHA384_ASN1,
+        _ => return Err(CertificateError::BadSignature.into()),
+    };
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0)
+        .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+    UnparsedPublicKey::new(algorithm, &cert.public_key().subject_public_key.data)
+        .verify(message, dss.signature())
+        .map_err(|_| CertificateError::BadSignature)?;
+    Ok(HandshakeSignatureValid::assertion())
+}
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        VERIFY_SCHEMES.to_vec()
+    }
+}
+
+fn client_config(identity: &Identity) -> Result<ClientConfig> {
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate))
+        .with_client_auth_cert(identity.cert_chain.clone(), identity.key.clone())?)
+}
+
+pub async fn connect(domain: &str, stream: TcpStream, identity: &Identity) -> Result<TlsStream> {
+    let server_name =
+        ServerName::try_from(domain).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let connector = futures_rustls::TlsConnector::from(Arc::new(client_config(identity)?));
+    Ok(connector.connect(server_name, stream).await?)
+}
+
+pub fn peer_certificate(stream: &TlsStream) -> Result<Option<Vec<u8>>> {
+    Ok(stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(|cert| cert.0.clone()))
+}
+
+// Registration is the first use of the central unit certificate, there is nothing to
+// verify it against yet. tls_info exposes it so register_device can pin it
+pub fn https_client_builder(identity: &Identity) -> Result<reqwest::ClientBuilder> {
+    Ok(reqwest::Client::builder()
+        .use_preconfigured_tls(client_config(identity)?)
+        .tls_info(true))
+}
+
+pub struct TlsAcceptor(futures_rustls::TlsAcceptor);
+
+impl TlsAcceptor {
+    pub async fn from_pkcs12(der: &[u8], password: &str) -> Result<Self> {
+        let identity = Identity::from_pkcs12(der, password)?;
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(identity.cert_chain, identity.key)?;
+        Ok(TlsAcceptor(Arc::new(config).into()))
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> Result<ServerTlsStream> {
+        Ok(self.0.accept(stream).await?)
+    }
+}
@@ -0,0 +1,267 @@
+use futures::io::{AsyncRead, AsyncWrite};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+use tracing::warn;
+
+use crate::api::Result;
+use crate::codec::{encode_frame, MessageType, MessageWrapper, FRAME_MAGIC};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+// One line of a trace file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceRecord {
+    pub direction: Direction,
+    pub message_id: u32,
+    pub message_type: MessageType,
+    pub priority: u8,
+    pub message: String,
+    // Milliseconds since the unix epoch
+    pub timestamp: u64,
+}
+
+impl TraceRecord {
+    pub fn new(direction: Direction, message: &MessageWrapper) -> Self {
+        TraceRecord {
+            direction,
+            message_id: message.message_id,
+            message_type: message.message_type,
+            priority: message.priority,
+            message: message.message.to_string(),
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or(0),
+        }
+    }
+
+    pub fn to_message(&self) -> MessageWrapper {
+        MessageWrapper::new(self.message_type, self.message_id, &self.message)
+            .with_priority(self.priority)
+    }
+}
+
+// Records every message a CuClient sends and receives as JSON lines, enabled by
+// setting CuClientOptions::tracer. Lines are written by a thread of their own so the
+// connection tasks never block on the file
+#[derive(Debug)]
+pub struct Tracer {
+    lines: Mutex<Option<Sender<String>>>,
+    writer: Mutex<Option<JoinHandle<io::Result<()>>>>,
+}
+
+// Tracing must not break the connection, the first write error is logged and ends
+// the trace, close returns it
+fn write_lines(mut file: File, lines: Receiver<String>) -> io::Result<()> {
+    for line in lines {
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!(error = %e, "writing the trace failed, tracing stopped");
+            return Err(e);
+        }
+    }
+    file.flush()
+}
+
+impl Tracer {
+    pub fn create(path: &str) -> Result<Self> {
+        let file = File::create(path)?;
+        let (lines, receiver) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("bswitch-trace".to_string())
+            .spawn(move || write_lines(file, receiver))?;
+        Ok(Tracer {
+            lines: Mutex::new(Some(lines)),
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    pub fn record(&self, direction: Direction, message: &MessageWrapper) {
+        let mut line = match serde_json::to_string(&TraceRecord::new(direction, message)) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(message_id = message.message_id, error = %e, "message not traced");
+                return;
+            }
+        };
+        line.push('\n');
+        if let Some(lines) = self.lines.lock().unwrap().as_ref() {
+            // Fails once the writer stopped on an error, which was logged already
+            let _ = lines.send(line);
+        }
+    }
+
+    // Writes the messages recorded so far and stops tracing, returns the write error
+    // that stopped the trace early if any
+    pub fn close(&self) -> Result<()> {
+        self.lines.lock().unwrap().take();
+        let writer = self.writer.lock().unwrap().take();
+        match writer.map(|writer| writer.join()) {
+            Some(Ok(result)) => Ok(result?),
+            Some(Err(_)) => Err(io::Error::other("trace writer panicked").into()),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn load_trace(path: &str) -> Result<Vec<TraceRecord>> {
+    let mut records = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            records.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(records)
+}
+
+// In-memory stream that plays a recorded session back to CuClient::from_stream.
+// Every frame the client writes consumes the next sent record, and the received
+// records following it are then readable, with response ids mapped to the ids the
+// client used. A frame other than the recorded one fails the write, so requests are
+// checked to come in the recorded order. The stream ends once the recording is
+// exhausted. Heartbeats should be disabled unless they were recorded
+pub struct ReplayTransport {
+    records: VecDeque<TraceRecord>,
+    // Recorded request id to the id of the replayed request
+    ids: HashMap<u32, u32>,
+    written: Vec<u8>,
+    readable: VecDeque<u8>,
+    reader: Option<Waker>,
+}
+
+impl ReplayTransport {
+    pub fn new(records: Vec<TraceRecord>) -> Self {
+        let mut transport = ReplayTransport {
+            records: records.into(),
+            ids: HashMap::new(),
+            written: Vec::new(),
+            readable: VecDeque::new(),
+            reader: None,
+        };
+        transport.release_received();
+        transport
+    }
+
+    pub fn from_file(path: &str) -> Result<Self> {
+        Ok(Self::new(load_trace(path)?))
+    }
+
+    fn release_received(&mut self) {
+        while let Some(record) = self.records.front() {
+            if record.direction != Direction::Received {
+                break;
+            }
+            let mut message = record.to_message();
+            if message.message_type == MessageType::Response {
+                if let Some(id) = self.ids.get(&message.message_id) {
+                    message.message_id = *id;
+                }
+            }
+            self.readable.extend(encode_frame(&message.serialize()));
+            self.records.pop_front();
+        }
+        if let Some(reader) = self.reader.take() {
+            reader.wake();
+        }
+    }
+
+    // Consumes the complete frames written so far
+    fn process_written(&mut self) -> io::Result<()> {
+        while self.written.len() >= 8 {
+            if self.written[..4] != FRAME_MAGIC {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "bad frame magic",
+                ));
+            }
+            let size = u32::from_le_bytes([
+                self.written[4],
+                self.written[5],
+                self.written[6],
+                self.written[7],
+            ]) as usize;
+            if self.written.len() < 8 + size {
+                break;
+            }
+            let frame: Vec<u8> = self.written.drain(..8 + size).skip(8).collect();
+            let message = MessageWrapper::deserialize(&frame)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            // Received records are released as soon as they are next, so the next
+            // record is the request expected from the client or the end of the recording
+            let record = match self.records.pop_front() {
+                Some(record) => record,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("request {} past the end of the recording", message.message),
+                    ))
+                }
+            };
+            if record.message_type != message.message_type || record.message != message.message {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "expected {:?} {}, the client sent {:?} {}",
+                        record.message_type, record.message, message.message_type, message.message
+                    ),
+                ));
+            }
+            self.ids.insert(record.message_id, message.message_id);
+            self.release_received();
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for ReplayTransport {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.readable.is_empty() {
+            if self.records.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            self.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let size = std::cmp::min(buf.len(), self.readable.len());
+        for (target, byte) in buf.iter_mut().zip(self.readable.drain(..size)) {
+            *target = byte;
+        }
+        Poll::Ready(Ok(size))
+    }
+}
+
+impl AsyncWrite for ReplayTransport {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.written.extend_from_slice(buf);
+        Poll::Ready(self.process_written().map(|_| buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use bswitch::api::*;
+use bswitch::blocking;
+use bswitch::keygen::{export_pkcs12, generate_keypair};
+use bswitch::mock::{default_cu_data, MockCentralUnit};
+use bswitch::protocol::CuClientOptions;
+use bswitch::runtime::{self, TcpListener};
+use bswitch::tls::Identity;
+
+fn device_identity() -> Identity {
+    let (key, cert) = generate_keypair("test@localhost", "Test device").unwrap();
+    let pkcs12 = export_pkcs12(&key, &cert, "test").unwrap();
+    Identity::from_pkcs12(&pkcs12, "test").unwrap()
+}
+
+// The mock keeps serving on the runtime after block_on returns
+fn start_mock() -> (MockCentralUnit, u32) {
+    runtime::block_on(async {
+        let mock = MockCentralUnit::new(default_cu_data()).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let server = mock.clone();
+        runtime::spawn(async move {
+            let _ = server.serve_protocol(listener).await;
+        });
+        (mock, port)
+    })
+}
+
+fn options() -> CuClientOptions {
+    CuClientOptions {
+        heartbeat_interval: None,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn blocking_client_round_trip() {
+    let (mock, port) = start_mock();
+    let client =
+        blocking::CuClient::with_options("127.0.0.1", port, device_identity(), options()).unwrap();
+    let mut notifications = client.notifications().unwrap();
+
+    let data = client.get_all().unwrap();
+    assert_eq!(data.mac, default_cu_data().mac);
+    client
+        .unit_operation(&UnitItemOperation::new(1, UnitType::Switch, 100))
+        .unwrap();
+    let item: UnitItem = notifications.next().unwrap().parse().unwrap();
+    assert_eq!(item.unit_id, 1);
+    assert_eq!(item.value, 100);
+    assert!(client.get_all().unwrap().unit(1).unwrap().is_on());
+
+    // Background tasks keep running between blocking calls
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(runtime::block_on(mock.requests()).len(), 3);
+}
+
+#[cfg(feature = "runtime-tokio")]
+#[test]
+fn blocking_client_fails_within_a_tokio_runtime() {
+    let (_mock, port) = start_mock();
+    let identity = device_identity();
+    let result = runtime::block_on(async move {
+        blocking::CuClient::with_options("127.0.0.1", port, identity, options()).map(|_| ())
+    });
+    match result.as_ref().map_err(|e| e.root()) {
+        Err(CombinedError::IoError(e)) => assert!(e.to_string().contains("tokio runtime")),
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("blocked within a tokio runtime"),
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use bswitch::api::*;
+use bswitch::hub::HubManager;
+use bswitch::keygen::{export_pkcs12, generate_keypair};
+use bswitch::mock::{default_cu_data, MockCentralUnit};
+use bswitch::protocol::CuClientOptions;
+use bswitch::reconnect::ReconnectPolicy;
+use bswitch::runtime::{self, TcpListener};
+use bswitch::tls::Identity;
+
+const OTHER_MAC: &str = "00:00:5e:00:53:99";
+const OTHER_NAME: &str = "Garden";
+
+fn device_identity() -> Identity {
+    static IDENTITY: OnceLock<Identity> = OnceLock::new();
+    IDENTITY
+        .get_or_init(|| {
+            let (key, cert) = generate_keypair("test@localhost", "Test device").unwrap();
+            let pkcs12 = export_pkcs12(&key, &cert, "test").unwrap();
+            Identity::from_pkcs12(&pkcs12, "test").unwrap()
+        })
+        .clone()
+}
+
+fn other_cu_data() -> CuData {
+    let mut data = default_cu_data();
+    data.mac = OTHER_MAC.to_string();
+    data.name = OTHER_NAME.to_string();
+    data
+}
+
+async fn start_mock(data: CuData) -> (MockCentralUnit, u32) {
+    let mock = MockCentralUnit::new(data).await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+    let server = mock.clone();
+    runtime::spawn(async move {
+        let _ = server.serve_protocol(listener).await;
+    });
+    (mock, port)
+}
+
+// Requests wait for the hub timeout instead of their own
+fn hub_manager() -> HubManager {
+    let options = CuClientOptions {
+        heartbeat_interval: None,
+        request_timeout: None,
+        ..Default::default()
+    };
+    HubManager::new(ReconnectPolicy::default(), options)
+}
+
+fn unit_count(data: &CuData) -> usize {
+    data.place
+        .iter()
+        .flat_map(|place| place.zones.iter())
+        .map(|zone| zone.items.len())
+        .sum()
+}
+
+#[test]
+fn hubs_are_found_by_mac_and_name() {
+    runtime::block_on(async {
+        let (_first, first_port) = start_mock(default_cu_data()).await;
+        let (_second, second_port) = start_mock(other_cu_data()).await;
+        let hubs = hub_manager();
+        let first_mac = hubs
+            .add("127.0.0.1", first_port, device_identity())
+            .await
+            .unwrap();
+        let second_mac = hubs
+            .add("127.0.0.1", second_port, device_identity())
+            .await
+            .unwrap();
+        assert_eq!(first_mac, default_cu_data().mac);
+        assert_eq!(second_mac, OTHER_MAC);
+        assert_eq!(hubs.hubs().len(), 2);
+
+        let by_mac = hubs.hub(&first_mac.to_uppercase()).unwrap();
+        assert_eq!(by_mac.mac(), first_mac);
+        let by_name = hubs.hub(OTHER_NAME).unwrap();
+        assert_eq!(by_name.mac(), OTHER_MAC);
+        assert!(hubs.hub("Attic").is_none());
+
+        let op = UnitItemOperation::new(1, UnitType::Switch, 100);
+        let err = hubs.unit_operation("Attic", &op).await.unwrap_err();
+        match err.root() {
+            CombinedError::ApiError(e) => assert_eq!(e.status, OperationStatus::DeviceNotFound),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        assert!(hubs.remove(OTHER_NAME));
+        assert!(hubs.hub(OTHER_MAC).is_none());
+        assert_eq!(hubs.hubs().len(), 1);
+    });
+}
+
+#[test]
+fn get_all_lists_a_hub_that_times_out_as_an_error() {
+    runtime::block_on(async {
+        let (_first, first_port) = start_mock(default_cu_data()).await;
+        let (second, second_port) = start_mock(other_cu_data()).await;
+        let hubs = hub_manager().with_timeout(Duration::from_millis(300));
+        hubs.add("127.0.0.1", first_port, device_identity())
+            .await
+            .unwrap();
+        hubs.add("127.0.0.1", second_port, device_identity())
+            .await
+            .unwrap();
+
+        let inventory = hubs.get_all().await;
+        assert!(inventory.errors.is_empty());
+        assert_eq!(inventory.units.len(), 2 * unit_count(&default_cu_data()));
+
+        second.pause().await;
+        let inventory = hubs.get_all().await;
+        assert_eq!(inventory.units.len(), unit_count(&default_cu_data()));
+        assert!(inventory
+            .units
+            .iter()
+            .all(|unit| unit.hub_mac == default_cu_data().mac));
+        assert_eq!(inventory.errors.len(), 1);
+        let (mac, err) = &inventory.errors[0];
+        assert_eq!(mac, OTHER_MAC);
+        assert!(matches!(err.root(), CombinedError::Timeout(_)));
+    });
+}
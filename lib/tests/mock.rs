@@ -0,0 +1,631 @@
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::StreamExt;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex as StdMutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use bswitch::api::*;
+use bswitch::keygen::{export_pkcs12, generate_keypair};
+use bswitch::mock::{default_cu_data, MockCentralUnit};
+use bswitch::protocol::{
+    fetch_certificate_fingerprint, CuClient, CuClientOptions, DEFAULT_HEARTBEAT_REQUEST,
+    PRIORITY_HIGH, PRIORITY_NORMAL,
+};
+use bswitch::reconnect::{ConnectionEvent, ReconnectPolicy, ResilientCuClient};
+use bswitch::runtime::{self, TcpListener, UdpSocket};
+use bswitch::tls::Identity;
+
+// Generated once, RSA key generation is slow in debug builds
+fn device_identity() -> Identity {
+    static IDENTITY: OnceLock<Identity> = OnceLock::new();
+    IDENTITY
+        .get_or_init(|| {
+            let (key, cert) = generate_keypair("test@localhost", "Test device").unwrap();
+            let pkcs12 = export_pkcs12(&key, &cert, "test").unwrap();
+            Identity::from_pkcs12(&pkcs12, "test").unwrap()
+        })
+        .clone()
+}
+
+// Serves the CU protocol of a mock with the default model on a free localhost port
+async fn start_mock() -> (MockCentralUnit, u32) {
+    let mock = MockCentralUnit::new(default_cu_data()).await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+    let server = mock.clone();
+    runtime::spawn(async move {
+        let _ = server.serve_protocol(listener).await;
+    });
+    (mock, port)
+}
+
+async fn connect_pinned(port: u32, pinned_fingerprint: Option<String>) -> Result<CuClient> {
+    let options = CuClientOptions {
+        heartbeat_interval: None,
+        pinned_fingerprint,
+        ..Default::default()
+    };
+    CuClient::with_options("127.0.0.1", port, device_identity(), options).await
+}
+
+async fn connect(port: u32) -> CuClient {
+    connect_pinned(port, None).await.unwrap()
+}
+
+async fn connect_with_heartbeat(port: u32, heartbeat_request: &str) -> CuClient {
+    let options = CuClientOptions {
+        request_timeout: Some(Duration::from_millis(300)),
+        heartbeat_interval: Some(Duration::from_millis(100)),
+        heartbeat_request: heartbeat_request.to_string(),
+        ..Default::default()
+    };
+    CuClient::with_options("127.0.0.1", port, device_identity(), options)
+        .await
+        .unwrap()
+}
+
+#[derive(Default)]
+struct Gate {
+    open: bool,
+    // Writer waiting for the gate to open
+    blocked: Option<Waker>,
+}
+
+// Client stream whose writes stay pending until the gate opens, so requests queue up
+// behind the one the client is writing
+struct GatedStream<S> {
+    inner: S,
+    gate: Arc<StdMutex<Gate>>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for GatedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for GatedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        {
+            let mut gate = self.gate.lock().unwrap();
+            if !gate.open {
+                gate.blocked = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+async fn wait_until_blocked(gate: &StdMutex<Gate>) {
+    while gate.lock().unwrap().blocked.is_none() {
+        runtime::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+fn open(gate: &StdMutex<Gate>) {
+    let mut gate = gate.lock().unwrap();
+    gate.open = true;
+    if let Some(writer) = gate.blocked.take() {
+        writer.wake();
+    }
+}
+
+// Waits until the mock has read the given number of requests
+async fn wait_for_requests(mock: &MockCentralUnit, count: usize) {
+    while mock.requests().await.len() < count {
+        runtime::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[test]
+fn unit_operation_changes_the_model_and_notifies() {
+    runtime::block_on(async {
+        let (mock, port) = start_mock().await;
+        let client = connect(port).await;
+        let notifications = client.notifications();
+        futures::pin_mut!(notifications);
+
+        let data = client.get_all().await.unwrap();
+        assert_eq!(data.mac, default_cu_data().mac);
+        let light = data.unit(1).unwrap();
+        assert_eq!(light.unit_type, UnitType::Switch);
+        assert!(!light.is_on());
+
+        client
+            .unit_operation(&UnitItemOperation::new(1, light.unit_type, 100))
+            .await
+            .unwrap();
+        let notification = runtime::timeout(Duration::from_secs(5), notifications.next())
+            .await
+            .expect("no notification after UNOP")
+            .unwrap();
+        let item: UnitItem = notification.parse().unwrap();
+        assert_eq!(item.unit_id, 1);
+        assert_eq!(item.value, 100);
+
+        assert!(client.get_all().await.unwrap().unit(1).unwrap().is_on());
+        assert!(mock.data().await.unit(1).unwrap().is_on());
+    });
+}
+
+#[test]
+fn every_subscriber_receives_every_notification() {
+    runtime::block_on(async {
+        let (_mock, port) = start_mock().await;
+        let client = connect(port).await;
+        let first = client.notifications();
+        let second = client.notifications();
+        futures::pin_mut!(first, second);
+
+        for value in [100, 0] {
+            client
+                .unit_operation(&UnitItemOperation::new(1, UnitType::Switch, value))
+                .await
+                .unwrap();
+        }
+        for notifications in [&mut first, &mut second] {
+            let values: Vec<i32> = notifications
+                .take(2)
+                .map(|notification| notification.parse::<UnitItem>().unwrap().value)
+                .collect()
+                .await;
+            assert_eq!(values, vec![100, 0]);
+        }
+    });
+}
+
+#[test]
+fn oldest_notifications_are_dropped_when_the_subscriber_lags() {
+    runtime::block_on(async {
+        let (_mock, port) = start_mock().await;
+        let client = connect(port).await;
+        let notifications = client.notifications();
+        futures::pin_mut!(notifications);
+
+        // The mock pushes the notification of an operation before its response, so
+        // all of them have been read once the last operation returns
+        for value in 1..=300 {
+            client
+                .unit_operation(&UnitItemOperation::new(1, UnitType::Switch, value))
+                .await
+                .unwrap();
+        }
+        let oldest = notifications.next().await.unwrap();
+        assert_eq!(oldest.parse::<UnitItem>().unwrap().value, 300 - 256 + 1);
+    });
+}
+
+#[test]
+fn concurrent_requests_get_their_own_response() {
+    runtime::block_on(async {
+        let (mock, port) = start_mock().await;
+        let client = connect(port).await;
+        mock.pause().await;
+
+        // The mock answers held requests newest first
+        let op = UnitItemOperation::new(1, UnitType::Switch, 100);
+        let (data, status, ()) =
+            futures::join!(client.get_all(), client.unit_operation(&op), async {
+                wait_for_requests(&mock, 2).await;
+                mock.resume().await;
+            });
+        assert_eq!(data.unwrap().mac, default_cu_data().mac);
+        assert_eq!(status.unwrap().status, OperationStatus::OK);
+    });
+}
+
+#[test]
+fn queued_requests_are_written_by_priority() {
+    runtime::block_on(async {
+        let mock = MockCentralUnit::new(default_cu_data()).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = mock.clone();
+        runtime::spawn(async move {
+            let stream = runtime::accept(&listener).await.unwrap();
+            let _ = server.serve_stream(stream).await;
+        });
+        let gate = Arc::new(StdMutex::new(Gate::default()));
+        let stream = GatedStream {
+            inner: runtime::connect(&address).await.unwrap(),
+            gate: Arc::clone(&gate),
+        };
+        let options = CuClientOptions {
+            heartbeat_interval: None,
+            ..Default::default()
+        };
+        let client = CuClient::from_stream(stream, options);
+
+        let first = client.request_with_priority("GETA", PRIORITY_NORMAL);
+        let queued = async {
+            // The writer is stuck on the first request, the next ones are queued
+            wait_until_blocked(&gate).await;
+            let queued = futures::future::join3(
+                client.request_with_priority("GETA", PRIORITY_NORMAL),
+                client.request_with_priority("GETA", PRIORITY_NORMAL),
+                client.request_with_priority("GETA", PRIORITY_HIGH),
+            );
+            futures::join!(queued, async { open(&gate) }).0
+        };
+        let (first, (second, third, high)) = futures::join!(first, queued);
+        let ids: Vec<u32> = [first, second, third, high]
+            .into_iter()
+            .map(|response| response.unwrap().message_id)
+            .collect();
+
+        let written: Vec<(u32, u8)> = mock
+            .requests()
+            .await
+            .iter()
+            .map(|request| (request.message_id, request.priority))
+            .collect();
+        assert_eq!(
+            written,
+            vec![
+                (ids[0], PRIORITY_NORMAL),
+                (ids[3], PRIORITY_HIGH),
+                (ids[1], PRIORITY_NORMAL),
+                (ids[2], PRIORITY_NORMAL),
+            ]
+        );
+    });
+}
+
+#[test]
+fn unknown_unit_is_reported() {
+    runtime::block_on(async {
+        let (_mock, port) = start_mock().await;
+        let client = connect(port).await;
+        let err = client
+            .unit_operation(&UnitItemOperation::new(99, UnitType::Switch, 100))
+            .await
+            .unwrap_err();
+        match err.root() {
+            CombinedError::ApiError(e) => assert_eq!(e.status, OperationStatus::DeviceNotFound),
+            e => panic!("expected an API error, got {}", e),
+        }
+    });
+}
+
+#[test]
+fn request_times_out_when_the_unit_stops_responding() {
+    runtime::block_on(async {
+        let (mock, port) = start_mock().await;
+        let options = CuClientOptions {
+            request_timeout: Some(Duration::from_millis(200)),
+            heartbeat_interval: None,
+            ..Default::default()
+        };
+        let client = CuClient::with_options("127.0.0.1", port, device_identity(), options)
+            .await
+            .unwrap();
+        mock.pause().await;
+        let err = client.get_all().await.unwrap_err();
+        assert!(matches!(err.root(), CombinedError::Timeout(_)));
+
+        // A late response is dropped and the connection stays usable
+        mock.resume().await;
+        client.get_all().await.unwrap();
+    });
+}
+
+#[test]
+fn heartbeat_is_sent_on_idle_connections() {
+    runtime::block_on(async {
+        let (mock, port) = start_mock().await;
+        let client = connect_with_heartbeat(port, "PING").await;
+        runtime::sleep(Duration::from_millis(350)).await;
+        let requests = mock.requests().await;
+        assert!(requests.iter().any(|request| request.message == "PING"));
+        assert!(requests
+            .iter()
+            .all(|request| request.message != GetAll::OPCODE));
+        // The mock answers the unknown request with an error, which isn't a timeout
+        client.get_all().await.unwrap();
+    });
+}
+
+#[test]
+fn heartbeat_closes_the_connection_when_the_unit_stops_responding() {
+    runtime::block_on(async {
+        let (mock, port) = start_mock().await;
+        let client = connect_with_heartbeat(port, DEFAULT_HEARTBEAT_REQUEST).await;
+        let notifications = client.notifications();
+        futures::pin_mut!(notifications);
+        mock.pause().await;
+
+        // The notification stream ends once the connection is closed
+        let closed = runtime::timeout(Duration::from_secs(5), notifications.next())
+            .await
+            .expect("heartbeat didn't close the connection");
+        assert!(closed.is_none());
+        let err = client.get_all().await.unwrap_err();
+        match err.root() {
+            CombinedError::IoError(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted),
+            e => panic!("unexpected error {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn pinned_certificate_is_verified() {
+    runtime::block_on(async {
+        let (_mock, port) = start_mock().await;
+        let fingerprint = fetch_certificate_fingerprint("127.0.0.1", port, device_identity())
+            .await
+            .unwrap();
+        assert_eq!(
+            connect(port).await.certificate_fingerprint(),
+            Some(fingerprint.as_str())
+        );
+
+        // Pins are compared regardless of case
+        let pinned = connect_pinned(port, Some(fingerprint.to_ascii_uppercase())).await;
+        assert!(pinned.is_ok());
+
+        let mismatch = connect_pinned(port, Some("00".repeat(32))).await;
+        match mismatch.as_ref().map_err(|e| e.root()) {
+            Err(CombinedError::CertificatePinError(e)) => {
+                assert_eq!(e.actual.as_deref(), Some(fingerprint.as_str()))
+            }
+            Err(e) => panic!("expected a pin error, got {}", e),
+            Ok(_) => panic!("connected despite the pin mismatch"),
+        }
+    });
+}
+
+#[test]
+fn shutter_moves_to_the_set_position() {
+    runtime::block_on(async {
+        let (mock, port) = start_mock().await;
+        let client = connect(port).await;
+        client.set_shutter_position(5, 30).await.unwrap();
+        assert_eq!(client.shutter_position(5).await.unwrap(), 30);
+        assert_eq!(mock.data().await.unit(5).unwrap().value, 30);
+        client.close_shutter(5).await.unwrap();
+        assert_eq!(client.shutter_position(5).await.unwrap(), SHUTTER_CLOSED);
+    });
+}
+
+#[cfg(feature = "experimental")]
+#[test]
+fn thermostat_settings_are_changed_one_at_a_time() {
+    runtime::block_on(async {
+        let (mock, port) = start_mock().await;
+        let client = connect(port).await;
+        let before = client.thermostat(6).await.unwrap();
+        let state = client
+            .set_thermostat_mode(6, ThermostatMode::Heat)
+            .await
+            .unwrap();
+        assert_eq!(state.mode, ThermostatMode::Heat);
+        assert_eq!(state.fan, before.fan);
+        let after = mock
+            .data()
+            .await
+            .unit(6)
+            .unwrap()
+            .thermostat
+            .clone()
+            .unwrap();
+        assert_eq!(after.mode, ThermostatMode::Heat);
+        assert_eq!(after.target_temperature, before.target_temperature);
+    });
+}
+
+#[test]
+fn discovery_finds_the_mock() {
+    runtime::block_on(async {
+        let mock = MockCentralUnit::new(default_cu_data()).await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+        let server = mock.clone();
+        runtime::spawn(async move {
+            let _ = server.serve_discovery(socket).await;
+        });
+
+        let units = discover_central_units_at(&address, true).await.unwrap();
+        assert_eq!(units.len(), 1);
+        assert_eq!(units[0].mac, default_cu_data().mac);
+        assert_eq!(units[0].CUIP, "127.0.0.1");
+        // Discovery answers carry no units
+        assert!(units[0].place.is_none());
+    });
+}
+
+#[test]
+fn device_registers_with_the_mock() {
+    runtime::block_on(async {
+        let mock = MockCentralUnit::new(default_cu_data()).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = mock.clone();
+        runtime::spawn(async move {
+            let _ = server.serve_registration(listener).await;
+        });
+
+        let (_, cert) = generate_keypair("test@localhost", "Test device").unwrap();
+        let params = RegisterDeviceParams {
+            device: "Test device".to_string(),
+            device_certificate: base64::encode(&cert),
+            email: "test@localhost".to_string(),
+            key: "key".to_string(),
+            name: "admin".to_string(),
+            password: "password".to_string(),
+            pin: "".to_string(),
+        };
+        let client = get_default_https_client().await.unwrap();
+        let response = register_device_at(&client, "127.0.0.1", port, &params)
+            .await
+            .unwrap();
+        assert_eq!(response.status.status, OperationStatus::OK);
+
+        // The certificate pinned at registration is the one later connections verify
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cu_port = listener.local_addr().unwrap().port() as u32;
+        runtime::spawn(async move {
+            let _ = mock.serve_protocol(listener).await;
+        });
+        let client = connect_pinned(cu_port, Some(response.certificate_fingerprint))
+            .await
+            .unwrap();
+        client.get_all().await.unwrap();
+    });
+}
+
+// Serves the CU protocol of the mock on the given address until the mock is stopped
+async fn serve_at(mock: &MockCentralUnit, address: &str) {
+    let listener = TcpListener::bind(address).await.unwrap();
+    let server = mock.clone();
+    runtime::spawn(async move {
+        let _ = server.serve_protocol(listener).await;
+    });
+}
+
+// Fast reconnects, with discovery probes sent to a socket that never answers
+async fn reconnect_policy(max_attempts: u32) -> (ReconnectPolicy, UdpSocket) {
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let policy = ReconnectPolicy {
+        initial_backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_millis(200),
+        max_attempts: Some(max_attempts),
+        discovery_address: silent.local_addr().unwrap().to_string(),
+        discovery_timeout: Duration::from_millis(100),
+        ..Default::default()
+    };
+    (policy, silent)
+}
+
+async fn connect_resilient(ip: &str, port: u32, policy: ReconnectPolicy) -> ResilientCuClient {
+    let options = CuClientOptions {
+        heartbeat_interval: None,
+        ..Default::default()
+    };
+    ResilientCuClient::with_options(ip, port, device_identity(), policy, options)
+        .await
+        .unwrap()
+}
+
+async fn next_event<S: futures::Stream<Item = ConnectionEvent> + Unpin>(
+    events: &mut S,
+) -> ConnectionEvent {
+    runtime::timeout(Duration::from_secs(10), events.next())
+        .await
+        .expect("no connection event")
+        .unwrap()
+}
+
+#[test]
+fn resilient_client_reconnects_after_a_restart() {
+    runtime::block_on(async {
+        let (mock, port) = start_mock().await;
+        let (policy, _silent) = reconnect_policy(20).await;
+        let client = connect_resilient("127.0.0.1", port, policy).await;
+        let events = client.events();
+        let notifications = client.notifications();
+        futures::pin_mut!(events, notifications);
+
+        mock.stop().await;
+        assert!(matches!(
+            next_event(&mut events).await,
+            ConnectionEvent::Disconnected
+        ));
+        serve_at(&mock, &format!("127.0.0.1:{}", port)).await;
+        match next_event(&mut events).await {
+            ConnectionEvent::Reconnected { ip, data } => {
+                assert_eq!(ip, "127.0.0.1");
+                assert_eq!(data.mac, default_cu_data().mac);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        // The subscription follows the new connection
+        client
+            .unit_operation(&UnitItemOperation::new(2, UnitType::Switch, 0))
+            .await
+            .unwrap();
+        let notification = runtime::timeout(Duration::from_secs(5), notifications.next())
+            .await
+            .expect("no notification after the reconnect")
+            .unwrap();
+        let item: UnitItem = notification.parse().unwrap();
+        assert_eq!(item.unit_id, 2);
+    });
+}
+
+#[test]
+fn resilient_client_gives_up_after_max_attempts() {
+    runtime::block_on(async {
+        let (mock, port) = start_mock().await;
+        let (policy, _silent) = reconnect_policy(3).await;
+        let client = connect_resilient("127.0.0.1", port, policy).await;
+        let events = client.events();
+        futures::pin_mut!(events);
+        mock.stop().await;
+        next_event(&mut events).await;
+
+        let start = std::time::Instant::now();
+        let err = client.get_all().await.unwrap_err();
+        assert!(matches!(err.root(), CombinedError::IoError(_)));
+        // Two backoffs between three attempts, 50ms then doubled
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    });
+}
+
+#[test]
+fn resilient_client_rediscovers_a_moved_central_unit() {
+    runtime::block_on(async {
+        let (mock, port) = start_mock().await;
+        let (policy, _silent) = reconnect_policy(20).await;
+        let discovery = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        let policy = ReconnectPolicy {
+            discovery_address: discovery.local_addr().unwrap().to_string(),
+            ..policy
+        };
+        let client = connect_resilient("127.0.0.1", port, policy).await;
+        let events = client.events();
+        futures::pin_mut!(events);
+
+        // Another central unit got the old IP, the mock moved to 127.0.0.2
+        mock.stop().await;
+        next_event(&mut events).await;
+        let mut other_data = default_cu_data();
+        other_data.mac = "00:00:5e:00:53:02".to_string();
+        let other = MockCentralUnit::new(other_data).await.unwrap();
+        serve_at(&other, &format!("127.0.0.1:{}", port)).await;
+        serve_at(&mock, &format!("127.0.0.2:{}", port)).await;
+        let server = mock.clone();
+        runtime::spawn(async move {
+            let _ = server.serve_discovery(discovery).await;
+        });
+
+        match next_event(&mut events).await {
+            ConnectionEvent::Reconnected { ip, data } => {
+                assert_eq!(ip, "127.0.0.2");
+                assert_eq!(data.mac, default_cu_data().mac);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(client.ip(), "127.0.0.2");
+        client.get_all().await.unwrap();
+    });
+}
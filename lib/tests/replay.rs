@@ -0,0 +1,167 @@
+use futures::StreamExt;
+use std::io::Write;
+use std::time::Duration;
+
+use bswitch::api::*;
+use bswitch::codec::{MessageType, MessageWrapper};
+use bswitch::mock::default_cu_data;
+use bswitch::protocol::{CuClient, CuClientOptions};
+use bswitch::runtime;
+use bswitch::trace::{load_trace, Direction, ReplayTransport, TraceRecord, Tracer};
+
+fn record(direction: Direction, message_type: MessageType, id: u32, message: &str) -> TraceRecord {
+    TraceRecord::new(direction, &MessageWrapper::new(message_type, id, message))
+}
+
+// A GETA and a UNOP followed by its notification, with message ids other than the ones
+// the replaying client picks
+fn recorded_session() -> Vec<TraceRecord> {
+    let data = serde_json::to_string(&default_cu_data()).unwrap();
+    let operation = UnitItemOperation::new(1, UnitType::Switch, 100);
+    let ok = serde_json::to_string(&CuStatus {
+        status: OperationStatus::OK,
+    })
+    .unwrap();
+    let mut light = default_cu_data().unit(1).unwrap().clone();
+    light.value = 100;
+    vec![
+        record(Direction::Sent, MessageType::Request, 41, GetAll::OPCODE),
+        record(Direction::Received, MessageType::Response, 41, &data),
+        record(
+            Direction::Sent,
+            MessageType::Request,
+            42,
+            &UnitOperation::encode(&operation).unwrap(),
+        ),
+        record(Direction::Received, MessageType::Response, 42, &ok),
+        record(
+            Direction::Received,
+            MessageType::Notification,
+            7,
+            &serde_json::to_string(&light).unwrap(),
+        ),
+    ]
+}
+
+fn trace_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("bswitch-{}-{}.jsonl", name, std::process::id()));
+    path.to_str().unwrap().to_string()
+}
+
+fn write_trace(records: &[TraceRecord]) -> String {
+    let path = trace_path("replay");
+    let mut file = std::fs::File::create(&path).unwrap();
+    for record in records {
+        writeln!(file, "{}", serde_json::to_string(record).unwrap()).unwrap();
+    }
+    path
+}
+
+#[test]
+fn recorded_session_replays_through_the_client() {
+    let path = write_trace(&recorded_session());
+    let transport = ReplayTransport::from_file(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    runtime::block_on(async {
+        let options = CuClientOptions {
+            heartbeat_interval: None,
+            ..Default::default()
+        };
+        let client = CuClient::from_stream(transport, options);
+        let notifications = client.notifications();
+        futures::pin_mut!(notifications);
+
+        let data = client.get_all().await.unwrap();
+        assert_eq!(data.mac, default_cu_data().mac);
+        assert!(!data.unit(1).unwrap().is_on());
+
+        let status = client
+            .unit_operation(&UnitItemOperation::new(1, UnitType::Switch, 100))
+            .await
+            .unwrap();
+        assert_eq!(status.status, OperationStatus::OK);
+
+        let notification = runtime::timeout(Duration::from_secs(5), notifications.next())
+            .await
+            .expect("no notification replayed")
+            .unwrap();
+        assert_eq!(notification.message_id, 7);
+        let item: UnitItem = notification.parse().unwrap();
+        assert_eq!(item.unit_id, 1);
+        assert!(item.is_on());
+    });
+}
+
+#[test]
+fn requests_past_the_recording_fail() {
+    runtime::block_on(async {
+        let options = CuClientOptions {
+            heartbeat_interval: None,
+            request_timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+        let client = CuClient::from_stream(ReplayTransport::new(Vec::new()), options);
+        let err = client.get_all().await.unwrap_err();
+        assert!(
+            matches!(err.root(), CombinedError::IoError(_)),
+            "expected the connection to close, got {}",
+            err
+        );
+    });
+}
+
+#[test]
+fn requests_out_of_the_recorded_order_fail() {
+    runtime::block_on(async {
+        let options = CuClientOptions {
+            heartbeat_interval: None,
+            request_timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+        let client = CuClient::from_stream(ReplayTransport::new(recorded_session()), options);
+        let err = client
+            .unit_operation(&UnitItemOperation::new(1, UnitType::Switch, 100))
+            .await
+            .unwrap_err();
+        match err.root() {
+            CombinedError::IoError(e) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+            e => panic!("unexpected error {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn replayed_session_is_traced() {
+    let path = trace_path("trace");
+    let tracer = std::sync::Arc::new(Tracer::create(&path).unwrap());
+    runtime::block_on(async {
+        let options = CuClientOptions {
+            heartbeat_interval: None,
+            tracer: Some(tracer.clone()),
+            ..Default::default()
+        };
+        let client = CuClient::from_stream(ReplayTransport::new(recorded_session()), options);
+        client.get_all().await.unwrap();
+    });
+    tracer.close().unwrap();
+    let records = load_trace(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let expected = &recorded_session()[..2];
+    assert_eq!(records.len(), expected.len());
+    for (record, expected) in records.iter().zip(expected) {
+        assert_eq!(record.direction, expected.direction);
+        assert_eq!(record.message_type, expected.message_type);
+        assert_eq!(record.message, expected.message);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn trace_write_errors_are_reported() {
+    let tracer = Tracer::create("/dev/full").unwrap();
+    let message = MessageWrapper::new(MessageType::Request, 1, GetAll::OPCODE);
+    tracer.record(Direction::Sent, &message);
+    assert!(tracer.close().is_err());
+}
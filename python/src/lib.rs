@@ -1,91 +1,175 @@
-use async_native_tls;
-use async_std::sync::{Arc, Mutex};
+use async_std::sync::Arc;
 use base64;
+use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
 use pyo3::wrap_pyfunction;
+use std::time::Duration;
 
 use bswitch::api::{
-    discover_central_units, get_default_https_client, register_device as register_device_bswitch,
-    Base64DecodeError, CombinedError, HttpsError, IoError, JSONDecodeError, PyApiError,
-    RegisterDeviceParams, TlsError, UnitItemOperation, Ut8DecodeError,
+    self, discover_central_units, get_default_https_client,
+    register_device as register_device_bswitch, Base64DecodeError, CombinedError, HttpsError,
+    IoError, JSONDecodeError, PyApiError, PyBksError, PyCertificatePinError, PyDecodeError,
+    PyFramingError, PyTimeoutError, RegisterDeviceParams, TlsError, UnitItemOperation,
+    Ut8DecodeError, DEFAULT_IDENTITY_PASSWORD, IDENTITY_PASSWORD_ENV, MAX_BRIGHTNESS,
+    SHUTTER_CLOSED, SHUTTER_OPEN,
 };
-use bswitch::keygen::generate_keypair;
+use bswitch::keygen::{export_pkcs12, generate_keypair};
 use bswitch::protocol::*;
+use bswitch::tls::Identity;
 
 #[pyclass(name = "CuClient")]
-pub struct PyCuClient(Arc<Mutex<CuClient>>);
+pub struct PyCuClient(Arc<CuClient>);
 
 #[pyclass]
 #[derive(Clone)]
 pub struct UnitItem {
     #[pyo3(get)]
     pub zone: String,
-    #[pyo3(get)]
-    pub name: String,
-    #[pyo3(get)]
-    pub unit_id: i32,
-    #[pyo3(get)]
-    pub value: i32,
-    #[pyo3(get)]
-    pub unit_type: i32,
+    pub unit: api::UnitItem,
 }
 
 #[pymethods]
 impl UnitItem {
+    #[getter]
+    pub fn name(&self) -> &str {
+        &self.unit.name
+    }
+
+    #[getter]
+    pub fn unit_id(&self) -> i32 {
+        self.unit.unit_id
+    }
+
+    #[getter]
+    pub fn value(&self) -> i32 {
+        self.unit.value
+    }
+
+    // Numeric type code as reported by the central unit
+    #[getter]
+    pub fn unit_type(&self) -> i32 {
+        self.unit.unit_type.code()
+    }
+
+    // Type name such as "switch" or "dimmer", "unknown" for codes the library
+    // doesn't know
+    #[getter]
+    pub fn unit_type_name(&self) -> &'static str {
+        self.unit.unit_type.name()
+    }
+
     pub fn __repr__(&self) -> PyResult<String> {
         Ok(format!(
             "UnitItem<name: {}, id: {}, value: {}, type: {}>",
-            self.name, self.unit_id, self.value, self.unit_type
+            self.unit.name, self.unit.unit_id, self.unit.value, self.unit.unit_type
         ))
     }
 
     pub fn is_on(&self) -> bool {
-        self.value == 100
+        self.unit.is_on()
+    }
+
+    // Level 0-100 of a dimmer, None for other units
+    #[getter]
+    pub fn brightness(&self) -> Option<u8> {
+        self.unit.brightness()
+    }
+
+    // Open percentage 0-100 of a shutter, None for other units
+    #[getter]
+    pub fn shutter_position(&self) -> Option<u8> {
+        self.unit.shutter_position()
+    }
+
+    // Minutes left before a timed power unit turns off, None for other units
+    #[getter]
+    pub fn remaining_minutes(&self) -> Option<u64> {
+        self.unit
+            .remaining_time()
+            .map(|remaining| remaining.as_secs() / 60)
     }
 }
 
 impl UnitItem {
     pub fn with_value(mut self, value: i32) -> Self {
-        self.value = value;
+        self.unit.value = value;
         return self;
     }
 }
 
-#[pymethods]
 impl PyCuClient {
-    #[staticmethod]
-    pub fn new(py: Python, ip: String, port: u32, certificate: Vec<u8>) -> PyResult<&PyAny> {
+    fn connect(
+        py: Python,
+        ip: String,
+        port: u32,
+        identity: Identity,
+        pinned_fingerprint: Option<String>,
+    ) -> PyResult<&PyAny> {
         pyo3_asyncio::async_std::future_into_py(py, async move {
-            let identity = async_native_tls::Identity::from_pkcs12(&certificate, "1234")
-                .map_err(|e| CombinedError::from(e))?;
-            let client = CuClient::new(&ip, port, identity)
+            let options = CuClientOptions {
+                pinned_fingerprint,
+                ..Default::default()
+            };
+            let client = CuClient::with_options(&ip, port, identity, options)
                 .await
                 .map_err(|e| CombinedError::from(e))?;
-            Ok(PyCuClient(Arc::new(Mutex::new(client))))
+            Ok(PyCuClient(Arc::new(client)))
         })
     }
+}
 
-    pub fn request<'p>(&mut self, py: Python<'p>, request: String) -> PyResult<&'p PyAny> {
+#[pymethods]
+impl PyCuClient {
+    #[staticmethod]
+    #[pyo3(signature = (ip, port, certificate, pinned_fingerprint = None, password = DEFAULT_IDENTITY_PASSWORD.to_string()))]
+    pub fn new(
+        py: Python,
+        ip: String,
+        port: u32,
+        certificate: Vec<u8>,
+        pinned_fingerprint: Option<String>,
+        password: String,
+    ) -> PyResult<&PyAny> {
+        let identity = Identity::from_pkcs12(&certificate, &password)?;
+        Self::connect(py, ip, port, identity, pinned_fingerprint)
+    }
+
+    // Identity given as a PEM certificate and private key instead of PKCS#12
+    #[staticmethod]
+    #[pyo3(signature = (ip, port, certificate_pem, key_pem, pinned_fingerprint = None))]
+    pub fn from_pem(
+        py: Python,
+        ip: String,
+        port: u32,
+        certificate_pem: Vec<u8>,
+        key_pem: Vec<u8>,
+        pinned_fingerprint: Option<String>,
+    ) -> PyResult<&PyAny> {
+        let identity = Identity::from_pem(&certificate_pem, &key_pem)?;
+        Self::connect(py, ip, port, identity, pinned_fingerprint)
+    }
+
+    #[pyo3(signature = (request, priority = PRIORITY_NORMAL))]
+    pub fn request<'p>(
+        &self,
+        py: Python<'p>,
+        request: String,
+        priority: u8,
+    ) -> PyResult<&'p PyAny> {
         let client = Arc::clone(&self.0);
         pyo3_asyncio::async_std::future_into_py(py, async move {
             Ok(client
-                .lock()
-                .await
-                .request(&request)
+                .request_with_priority(&request, priority)
                 .await
-                .map_err(|e| CombinedError::from(e))?)
+                .map_err(|e| CombinedError::from(e))?
+                .message)
         })
     }
 
-    pub fn get_all_items<'p>(&mut self, py: Python<'p>) -> PyResult<&'p PyAny> {
+    pub fn get_all_items<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
         let client = Arc::clone(&self.0);
         pyo3_asyncio::async_std::future_into_py(py, async move {
-            let resp = client
-                .lock()
-                .await
-                .get_all()
-                .await
-                .map_err(|e| CombinedError::from(e))?;
+            let resp = client.get_all().await.map_err(|e| CombinedError::from(e))?;
             let mut result: Vec<UnitItem> = Vec::new();
             let places = match resp.place {
                 Some(v) => v,
@@ -95,10 +179,7 @@ impl PyCuClient {
                 for item in &zone.items {
                     result.push(UnitItem {
                         zone: zone.name.to_owned(),
-                        name: item.name.to_owned(),
-                        unit_id: item.unit_id,
-                        value: item.value,
-                        unit_type: item.unit_type,
+                        unit: item.clone(),
                     })
                 }
             }
@@ -107,7 +188,7 @@ impl PyCuClient {
     }
 
     pub fn change_state<'p>(
-        &mut self,
+        &self,
         py: Python<'p>,
         item: UnitItem,
         new_state: i32,
@@ -115,13 +196,11 @@ impl PyCuClient {
         let client = Arc::clone(&self.0);
         pyo3_asyncio::async_std::future_into_py(py, async move {
             match client
-                .lock()
-                .await
-                .unit_operation(&UnitItemOperation {
+                .unit_operation(&UnitItemOperation::new(
+                    item.unit.unit_id,
+                    item.unit.unit_type,
                     new_state,
-                    unit_type: item.unit_type,
-                    unit_id: item.unit_id,
-                })
+                ))
                 .await
             {
                 Ok(_) => Ok(item.clone().with_value(new_state)),
@@ -130,11 +209,88 @@ impl PyCuClient {
         })
     }
 
-    pub fn turn_on<'p>(&mut self, py: Python<'p>, item: UnitItem) -> PyResult<&'p PyAny> {
+    // Levels above 100 are clamped, returns the item with the new level
+    pub fn set_brightness<'p>(
+        &self,
+        py: Python<'p>,
+        item: UnitItem,
+        level: u8,
+    ) -> PyResult<&'p PyAny> {
+        let client = Arc::clone(&self.0);
+        pyo3_asyncio::async_std::future_into_py(py, async move {
+            client.set_brightness(item.unit.unit_id, level).await?;
+            Ok(item.with_value(level.min(MAX_BRIGHTNESS) as i32))
+        })
+    }
+
+    // Moves the level by step from the level the central unit reports
+    pub fn step_brightness<'p>(
+        &self,
+        py: Python<'p>,
+        item: UnitItem,
+        step: i32,
+    ) -> PyResult<&'p PyAny> {
+        let client = Arc::clone(&self.0);
+        pyo3_asyncio::async_std::future_into_py(py, async move {
+            let level = client.step_brightness(item.unit.unit_id, step).await?;
+            Ok(item.with_value(level as i32))
+        })
+    }
+
+    pub fn open_shutter<'p>(&self, py: Python<'p>, item: UnitItem) -> PyResult<&'p PyAny> {
+        self.set_shutter_position(py, item, SHUTTER_OPEN)
+    }
+
+    pub fn close_shutter<'p>(&self, py: Python<'p>, item: UnitItem) -> PyResult<&'p PyAny> {
+        self.set_shutter_position(py, item, SHUTTER_CLOSED)
+    }
+
+    // Positions above 100 are clamped, returns the item with the new position
+    pub fn set_shutter_position<'p>(
+        &self,
+        py: Python<'p>,
+        item: UnitItem,
+        position: u8,
+    ) -> PyResult<&'p PyAny> {
+        let client = Arc::clone(&self.0);
+        pyo3_asyncio::async_std::future_into_py(py, async move {
+            client
+                .set_shutter_position(item.unit.unit_id, position)
+                .await?;
+            Ok(item.with_value(position.min(SHUTTER_OPEN) as i32))
+        })
+    }
+
+    // Turns a timed power unit on for the given minutes
+    pub fn start_timed_power<'p>(
+        &self,
+        py: Python<'p>,
+        item: UnitItem,
+        minutes: u32,
+    ) -> PyResult<&'p PyAny> {
+        let client = Arc::clone(&self.0);
+        pyo3_asyncio::async_std::future_into_py(py, async move {
+            let duration = Duration::from_secs(minutes as u64 * 60);
+            client
+                .start_timed_power(item.unit.unit_id, duration)
+                .await?;
+            Ok(item.with_value(minutes.min(i32::MAX as u32) as i32))
+        })
+    }
+
+    pub fn cancel_timed_power<'p>(&self, py: Python<'p>, item: UnitItem) -> PyResult<&'p PyAny> {
+        let client = Arc::clone(&self.0);
+        pyo3_asyncio::async_std::future_into_py(py, async move {
+            client.cancel_timed_power(item.unit.unit_id).await?;
+            Ok(item.with_value(0))
+        })
+    }
+
+    pub fn turn_on<'p>(&self, py: Python<'p>, item: UnitItem) -> PyResult<&'p PyAny> {
         self.change_state(py, item, 100)
     }
 
-    pub fn turn_off<'p>(&mut self, py: Python<'p>, item: UnitItem) -> PyResult<&'p PyAny> {
+    pub fn turn_off<'p>(&self, py: Python<'p>, item: UnitItem) -> PyResult<&'p PyAny> {
         self.change_state(py, item, 0)
     }
 }
@@ -144,16 +300,47 @@ fn discover_central_unit(py: Python) -> PyResult<&PyAny> {
     pyo3_asyncio::async_std::future_into_py(py, async { Ok(discover_central_units(true).await?) })
 }
 
+// Fingerprint to pass as pinned_fingerprint when creating a CuClient
 #[pyfunction]
+#[pyo3(name = "fetch_certificate_fingerprint")]
+#[pyo3(signature = (ip, port, certificate, password = DEFAULT_IDENTITY_PASSWORD.to_string()))]
+fn py_fetch_certificate_fingerprint(
+    py: Python,
+    ip: String,
+    port: u32,
+    certificate: Vec<u8>,
+    password: String,
+) -> PyResult<&PyAny> {
+    let identity = Identity::from_pkcs12(&certificate, &password)?;
+    pyo3_asyncio::async_std::future_into_py(py, async move {
+        Ok(fetch_certificate_fingerprint(&ip, port, identity).await?)
+    })
+}
+
+// Returns the device identity as PKCS#12 encrypted with password, or with the
+// BSWITCH_IDENTITY_PASSWORD environment variable when not given, and the fingerprint
+// of the central unit certificate to pass as pinned_fingerprint when connecting
+#[pyfunction]
+#[pyo3(signature = (ip, device_name, email, key, password = None))]
 fn register_device(
     py: Python,
     ip: String,
     device_name: String,
     email: String,
     key: String,
+    password: Option<String>,
 ) -> PyResult<&PyAny> {
+    let password = password
+        .or_else(|| std::env::var(IDENTITY_PASSWORD_ENV).ok())
+        .filter(|password| !password.is_empty())
+        .ok_or_else(|| {
+            PyValueError::new_err(format!(
+                "a password for the device identity is required, pass password or set {}",
+                IDENTITY_PASSWORD_ENV
+            ))
+        })?;
     pyo3_asyncio::async_std::future_into_py(py, async move {
-        let (pk, cert) = generate_keypair(&email, &device_name);
+        let (pk, cert) = generate_keypair(&email, &device_name)?;
         let client = get_default_https_client().await?;
         let params = RegisterDeviceParams {
             name: email.to_owned(),
@@ -162,15 +349,15 @@ fn register_device(
             password: "".to_owned(),
             pin: "".to_owned(),
             device: device_name,
-            device_certificate: base64::encode_config(cert.to_der().unwrap(), base64::URL_SAFE),
+            device_certificate: base64::encode_config(&cert, base64::URL_SAFE),
         };
-        register_device_bswitch(&client, &ip, &params)
+        let response = register_device_bswitch(&client, &ip, &params)
             .await
             .map_err(|e| CombinedError::from(e))?;
-        let pkcs12cert = openssl::pkcs12::Pkcs12::builder()
-            .build("1234", "device cert", &pk, &cert)
-            .unwrap();
-        Ok(pkcs12cert.to_der().map_err(|e| CombinedError::from(e))?)
+        Ok((
+            export_pkcs12(&pk, &cert, &password)?,
+            response.certificate_fingerprint,
+        ))
     })
 }
 
@@ -185,7 +372,16 @@ fn libpybswitch(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add("JSONDecodeError", _py.get_type::<JSONDecodeError>())?;
     m.add("Utf8DecodeError", _py.get_type::<Ut8DecodeError>())?;
     m.add("Base64DecodeError", _py.get_type::<Base64DecodeError>())?;
+    m.add("TimeoutError", _py.get_type::<PyTimeoutError>())?;
+    m.add("FramingError", _py.get_type::<PyFramingError>())?;
+    m.add("DecodeError", _py.get_type::<PyDecodeError>())?;
+    m.add(
+        "CertificatePinError",
+        _py.get_type::<PyCertificatePinError>(),
+    )?;
+    m.add("BksError", _py.get_type::<PyBksError>())?;
     m.add_function(wrap_pyfunction!(discover_central_unit, m)?)?;
     m.add_function(wrap_pyfunction!(register_device, m)?)?;
+    m.add_function(wrap_pyfunction!(py_fetch_certificate_fingerprint, m)?)?;
     Ok(())
 }